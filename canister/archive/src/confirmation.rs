@@ -0,0 +1,160 @@
+/*
+ ******************************************
+ *                                        *
+ *          Confirmation Types             *
+ *                                        *
+ ******************************************
+*/
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use rs_merkle::algorithms::Sha256;
+use rs_merkle::Hasher;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+const SIGNATURE_CANISTER: &str = "r34pn-oaaaa-aaaak-qinga-cai";
+const OWNER: &str = "ytoqu-ey42w-sb2ul-m7xgn-oc7xo-i4btp-kuxjc-b6pt4-dwdzu-kfqs4-nae";
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub enum ConfirmationStatus {
+    #[allow(unused)]
+    Pending, // 和signature canister保持一致, archive里不会出现
+    Confirmed(Confirmation),
+    Invalid,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Proof {
+    pub proof_bytes: Vec<u8>,
+    pub leaf_index: usize,
+    pub leaf_count: usize,                 // leaves of the batch merkle tree
+    pub leaf_digest: [u8; 32],             // blob digest
+    pub blob_size: usize,                  // blob size in bytes
+    pub timestamp: u128,                   // blob timestamp in nanos
+    pub storage_canisters: Vec<Principal>, // canisters holding the blob
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Confirmation {
+    pub root: [u8; 32],      // merkle root hash
+    pub proof: Proof,        // merkle proof
+    pub signature: String,   // hex encoded signature on the batch header hash
+    pub batch_index: u32,    // batch index
+    pub prev_hash: [u8; 32], // header hash of the previous batch
+    pub legacy: bool,        // leaf = digest, root is signed directly
+}
+
+// 和signature canister的merkle leaf一致
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ConfirmationLeaf {
+    pub digest: [u8; 32],
+    pub size: usize,
+    pub timestamp: u128,
+    pub canisters: Vec<Principal>,
+}
+
+impl ConfirmationLeaf {
+    // sha256(digest || size(u64 be) || timestamp(u128 be) || (len(u8) || principal)*)
+    pub fn hash(&self) -> [u8; 32] {
+        let mut bytes = Vec::with_capacity(32 + 8 + 16 + self.canisters.len() * 30);
+        bytes.extend_from_slice(&self.digest);
+        bytes.extend_from_slice(&(self.size as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        for canister in self.canisters.iter() {
+            let canister = canister.as_slice();
+            bytes.push(canister.len() as u8);
+            bytes.extend_from_slice(canister);
+        }
+        Sha256::hash(&bytes)
+    }
+}
+
+// signature canister 发送过来的过期batch
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct BatchConfirmation {
+    pub signature: Option<String>,
+    pub root: [u8; 32],
+    pub nodes: Vec<ConfirmationLeaf>,
+    pub index: u32,
+    pub prev_hash: [u8; 32],
+    pub timestamp: u64,       // sealed time in nanos
+    pub signing_cycles: u128, // cycles spent on signing the batch
+    pub legacy: bool,         // leaf = digest, root signed directly
+}
+
+impl Debug for BatchConfirmation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BatchConfirmation")
+            .field("index", &self.index)
+            .field("signature", &self.signature)
+            .field("root", &hex::encode(self.root))
+            .field("prev_hash", &hex::encode(self.prev_hash))
+            .field("timestamp", &self.timestamp)
+            .field("signing_cycles", &self.signing_cycles)
+            .field("legacy", &self.legacy)
+            .field(
+                "nodes",
+                &self
+                    .nodes
+                    .iter()
+                    .map(|leaf| hex::encode(leaf.digest))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Storable for BatchConfirmation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl BatchConfirmation {
+    pub fn leaf_hashes(&self) -> Vec<[u8; 32]> {
+        if self.legacy {
+            return self.nodes.iter().map(|leaf| leaf.digest).collect();
+        }
+        self.nodes.iter().map(ConfirmationLeaf::hash).collect()
+    }
+}
+
+// 新增的字段必须是Option: upgrade前保存的config里没有这个字段, 解码为None
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    pub signature_canister: Principal, // who can append batches
+    pub owner: Principal,              // who can change archive config
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            signature_canister: Principal::from_text(SIGNATURE_CANISTER).unwrap(),
+            owner: Principal::from_text(OWNER).unwrap(),
+        }
+    }
+}
+
+// config保存在stable memory里, upgrade以后恢复
+// 解码失败时trap, upgrade回滚, 不能用默认的config覆盖owner和signature_canister
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("decode stable config failed: {}", e)))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
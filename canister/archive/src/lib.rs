@@ -0,0 +1,163 @@
+//! # Archive
+//! 保存signature canister过期的BatchConfirmation, 用于事后的争议
+//!
+//! ## 保存batch
+//! - signature canister 批量调用 append_batches
+//! - batch index => BatchConfirmation 的map
+//! - hex encode digest => batch index 的map
+//!
+//! ## 获取confirmation
+//! - 和signature canister一样: 通过digest找到batch, 生成proof, 组成confirmation
+//!
+//! ## config
+//! - 保存在stable memory里, upgrade以后恢复
+
+use std::cell::RefCell;
+
+use candid::{candid_method, Principal};
+use ic_cdk::{caller, print};
+use ic_cdk_macros::{post_upgrade, query, update};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use rs_merkle::algorithms::Sha256;
+use rs_merkle::MerkleTree;
+
+use crate::confirmation::{BatchConfirmation, Config, Confirmation, ConfirmationStatus, Proof};
+
+mod confirmation;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    // archive config
+    static ARCHIVE_CONFIG: RefCell<Config> = RefCell::new(Config::default());
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // hex encode digest => batch index
+    static INDEX_MAP: RefCell<StableBTreeMap<String, u32, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(0)))
+    ));
+
+    // batch index => BatchConfirmation
+    static BATCH_CONFIRMATION: RefCell<StableBTreeMap<u32, BatchConfirmation, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(1)))
+    ));
+
+    // ARCHIVE_CONFIG的stable备份, update_config时写入, post_upgrade时恢复
+    static STABLE_CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(2))),
+        Config::default(),
+    ).unwrap());
+}
+
+// 只有signature canister可以写入, 重复的batch直接覆盖
+// 返回保存了的batch index, signature canister只删除这些batch
+#[update(name = "append_batches")]
+#[candid_method]
+fn append_batches(batches: Vec<BatchConfirmation>) -> Result<Vec<u32>, String> {
+    if !check_signature_canister(caller()) {
+        return Err("only signature canister can append batches".to_string());
+    }
+
+    let mut archived = Vec::with_capacity(batches.len());
+    for batch_confirmation in batches {
+        let batch_index = batch_confirmation.index;
+        INDEX_MAP.with_borrow_mut(|m| {
+            for leaf in batch_confirmation.nodes.iter() {
+                m.insert(hex::encode(leaf.digest), batch_index);
+            }
+        });
+        print(format!("archive batch: {:?}", batch_confirmation));
+        BATCH_CONFIRMATION.with_borrow_mut(|m| m.insert(batch_index, batch_confirmation));
+        archived.push(batch_index);
+    }
+
+    Ok(archived)
+}
+
+#[query(name = "get_confirmation")]
+#[candid_method(query)]
+fn get_confirmation(digest: [u8; 32]) -> ConfirmationStatus {
+    let batch_confirmation = match get_batch_by_digest(digest) {
+        Some(batch_confirmation) => batch_confirmation,
+        None => return ConfirmationStatus::Invalid,
+    };
+
+    let (signature, leaf_index) = match (
+        batch_confirmation.signature.clone(),
+        batch_confirmation
+            .nodes
+            .iter()
+            .position(|x| x.digest == digest),
+    ) {
+        (Some(signature), Some(leaf_index)) => (signature, leaf_index),
+        _ => return ConfirmationStatus::Invalid,
+    };
+
+    let merkle_tree = MerkleTree::<Sha256>::from_leaves(&batch_confirmation.leaf_hashes());
+    let proof_bytes = merkle_tree.proof(&[leaf_index]).to_bytes();
+    let leaf = &batch_confirmation.nodes[leaf_index];
+
+    let proof = Proof {
+        proof_bytes,
+        leaf_index,
+        leaf_count: batch_confirmation.nodes.len(),
+        leaf_digest: digest,
+        blob_size: leaf.size,
+        timestamp: leaf.timestamp,
+        storage_canisters: leaf.canisters.clone(),
+    };
+
+    ConfirmationStatus::Confirmed(Confirmation {
+        root: batch_confirmation.root,
+        proof,
+        signature,
+        batch_index: batch_confirmation.index,
+        prev_hash: batch_confirmation.prev_hash,
+        legacy: batch_confirmation.legacy,
+    })
+}
+
+#[query(name = "get_batch")]
+#[candid_method(query)]
+fn get_batch(batch_index: u32) -> Option<BatchConfirmation> {
+    BATCH_CONFIRMATION.with_borrow(|m| m.get(&batch_index))
+}
+
+#[query(name = "get_batch_by_digest")]
+#[candid_method(query)]
+fn get_batch_by_digest(digest: [u8; 32]) -> Option<BatchConfirmation> {
+    let batch_index = INDEX_MAP.with_borrow(|m| m.get(&hex::encode(digest)))?;
+    get_batch(batch_index)
+}
+
+#[update(name = "update_config")]
+#[candid_method]
+fn update_config(config: Config) {
+    assert!(check_owner(caller()), "only owner can update archive config");
+    STABLE_CONFIG.with_borrow_mut(|c| c.set(config.clone()).unwrap());
+    ARCHIVE_CONFIG.with_borrow_mut(|c| *c = config);
+}
+
+// 恢复upgrade之前的config
+#[post_upgrade]
+fn post_upgrade() {
+    let config = STABLE_CONFIG.with_borrow(|c| c.get().clone());
+    ARCHIVE_CONFIG.with_borrow_mut(|c| *c = config);
+}
+
+candid::export_service!();
+#[test]
+fn export_candid() {
+    println!("{:#?}", __export_service());
+}
+
+fn check_owner(c: Principal) -> bool {
+    c.eq(&ARCHIVE_CONFIG.with_borrow(|c| c.owner))
+}
+
+fn check_signature_canister(c: Principal) -> bool {
+    c.eq(&ARCHIVE_CONFIG.with_borrow(|c| c.signature_canister))
+}
@@ -9,16 +9,19 @@
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::Storable;
+use rs_merkle::algorithms::Sha256;
+use rs_merkle::{Hasher, MerkleTree};
 use serde::Serialize;
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::Debug;
 
 const REPLICA_NUM: usize = 1; // 1 blob, 1 canister replicas
 const COLLECTION_SIZE: usize = 11; // current subnets number, 20 subnets and 40 canisters
 
 const CONFIRMATION_BATCH_SIZE: usize = 12; // current size of the batch
-const CONFIRMATION_LIVE_TIME: u32 = 120961; // 1/12 * 1 week in secs = 12 * 60 * 24 * 7 + 1
+const CONFIRMATION_LIVE_TIME: u32 = 60 * 60 * 24 * 7; // 1 week in secs
+const CYCLES_RESERVE: u128 = 200_000_000_000; // 0.2T, 低于这个余额就暂停封装batch
 const CANISTER_COLLECTIONS: [[&str; REPLICA_NUM]; COLLECTION_SIZE] = [
     ["hxctj-oiaaa-aaaap-qhltq-cai"], // nl6hn-ja4yw-wvmpy-3z2jx-ymc34-pisx3-3cp5z-3oj4a-qzzny-jbsv3-4qe
     ["v3y75-6iaaa-aaaak-qikaa-cai"], // opn46-zyspe-hhmyp-4zu6u-7sbrh-dok77-m7dch-im62f-vyimr-a3n2c-4ae
@@ -40,35 +43,146 @@ pub enum ConfirmationStatus {
     Invalid,
 }
 
+// confirmation + IC certificate, 用于验证query的结果
+// certified data = root hash of (batch index => batch root) tree
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct CertifiedConfirmation {
+    pub status: ConfirmationStatus,
+    pub batch_index: Option<u32>, // Some if status is Confirmed
+    pub certificate: Vec<u8>,     // cbor encoded IC certificate
+    pub witness: Vec<u8>,         // cbor encoded witness of the batch root
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Proof {
     pub proof_bytes: Vec<u8>,
     pub leaf_index: usize,
-    pub leaf_digest: [u8; 32],
+    pub leaf_count: usize,                 // leaves of the batch merkle tree
+    pub leaf_digest: [u8; 32],             // blob digest
+    pub blob_size: usize,                  // blob size in bytes
+    pub timestamp: u128,                   // blob timestamp in nanos
+    pub storage_canisters: Vec<Principal>, // canisters holding the blob
+}
+
+// storage canister 存完整个blob以后上报的信息
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BlobInfo {
+    pub digest: [u8; 32],
+    pub size: usize,
+    pub canister: Principal, // storing canister
+    pub timestamp: u128,
+}
+
+// merkle tree的叶子节点, 叶子的hash = leaf_hash(digest, size, timestamp, canisters)
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ConfirmationLeaf {
+    pub digest: [u8; 32],
+    pub size: usize,
+    pub timestamp: u128,
+    pub canisters: Vec<Principal>,
+}
+
+impl ConfirmationLeaf {
+    pub fn hash(&self) -> [u8; 32] {
+        leaf_hash(&self.digest, self.size, self.timestamp, &self.canisters)
+    }
+}
+
+/// sha256(digest || size(u64 be) || timestamp(u128 be) || (len(u8) || principal)*)
+pub fn leaf_hash(
+    digest: &[u8; 32],
+    size: usize,
+    timestamp: u128,
+    canisters: &[Principal],
+) -> [u8; 32] {
+    let mut bytes = Vec::with_capacity(32 + 8 + 16 + canisters.len() * 30);
+    bytes.extend_from_slice(digest);
+    bytes.extend_from_slice(&(size as u64).to_be_bytes());
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    for canister in canisters {
+        let canister = canister.as_slice();
+        bytes.push(canister.len() as u8);
+        bytes.extend_from_slice(canister);
+    }
+    Sha256::hash(&bytes)
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Confirmation {
-    pub root: [u8; 32],    // merkle root hash
-    pub proof: Proof,      // merkle proof
-    pub signature: String, // hex encoded signature
+    pub root: [u8; 32],      // merkle root hash
+    pub proof: Proof,        // merkle proof
+    pub signature: String,   // hex encoded signature on the batch header hash
+    pub batch_index: u32,    // batch index
+    pub prev_hash: [u8; 32], // header hash of the previous batch
+    pub legacy: bool,        // upgrade前签名的batch: leaf = digest, 签名的是root
+}
+
+// 一个batch里多个digest共用一个multi-proof
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct BatchProof {
+    pub batch_index: u32,
+    pub root: [u8; 32],
+    pub signature: String,
+    pub proof_bytes: Vec<u8>,          // rs_merkle multi-proof
+    pub leaf_indices: Vec<usize>,      // ascending
+    pub leaves: Vec<ConfirmationLeaf>, // same order as leaf_indices
+    pub leaf_count: usize,             // total leaves of the batch
+    pub prev_hash: [u8; 32],           // header hash of the previous batch
+    pub legacy: bool,                  // upgrade前签名的batch: leaf = digest, 签名的是root
+}
+
+// 最新的已封装batch, 即hash chain的头
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct ChainHead {
+    pub batch_index: u32, // 0 => 还没有封装过batch
+    pub header_hash: [u8; 32],
+}
+
+// chain head保存在stable memory里, 链头的batch被删除以后仍然可以链接
+impl Storable for ChainHead {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 100,
+        is_fixed_size: false,
+    };
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct BatchConfirmation {
     pub signature: Option<String>,
     pub root: [u8; 32],
-    pub nodes: Vec<[u8; 32]>, // 12 个 blob的digest
+    pub nodes: Vec<ConfirmationLeaf>, // 12 个 blob的leaf
+    pub index: u32,                   // batch index
+    pub prev_hash: [u8; 32],          // 上一个batch的header hash, 组成hash chain
+    pub timestamp: u64,               // 封装的时间(nanos), 0 => 还没有封装
+    pub signing_cycles: u128,         // 签名实际花费的cycles, 0 => 还没有签名
+    pub legacy: bool,                 // upgrade前签名的batch: leaf = digest, 签名的是root
 }
 
 impl Debug for BatchConfirmation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("BatchConfirmation")
+            .field("index", &self.index)
             .field("signature", &self.signature)
             .field("root", &hex::encode(self.root))
+            .field("prev_hash", &hex::encode(self.prev_hash))
+            .field("timestamp", &self.timestamp)
+            .field("signing_cycles", &self.signing_cycles)
+            .field("legacy", &self.legacy)
             .field(
                 "nodes",
-                &self.nodes.iter().map(hex::encode).collect::<Vec<_>>(),
+                &self
+                    .nodes
+                    .iter()
+                    .map(|leaf| hex::encode(leaf.digest))
+                    .collect::<Vec<_>>(),
             )
             .finish()
     }
@@ -79,16 +193,112 @@ impl Storable for BatchConfirmation {
         Cow::Owned(Encode!(self).unwrap())
     }
 
+    // 旧版本的batch解码失败时按旧的格式解码, post_upgrade里再补全index和timestamp
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), LegacyBatchConfirmation).map(Self::from))
+            .unwrap()
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        // 1024 bytes > 实际使用(64 bytes signature + 12 * 32 bytes nodes) + candid = 530 bytes,
-        // encoded => 594
-        max_size: 1024,
-        is_fixed_size: false,
-    };
+    // leaf 包含 size, timestamp 和 storage canisters, batch size 也可以调整, 所以不设上限
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// 旧版本的batch: leaf只有digest, 没有index, hash chain和封装时间
+#[derive(CandidType, Deserialize)]
+struct LegacyBatchConfirmation {
+    signature: Option<String>,
+    root: [u8; 32],
+    nodes: Vec<[u8; 32]>,
+}
+
+// index = 0 => 还没有迁移, 真实的index是map的key
+// 已签名的batch保留旧的root和签名, 按旧的格式生成proof
+impl From<LegacyBatchConfirmation> for BatchConfirmation {
+    fn from(legacy: LegacyBatchConfirmation) -> Self {
+        Self {
+            legacy: legacy.signature.is_some(),
+            signature: legacy.signature,
+            root: legacy.root,
+            nodes: legacy
+                .nodes
+                .into_iter()
+                .map(|digest| ConfirmationLeaf {
+                    digest,
+                    size: 0,
+                    timestamp: 0,
+                    canisters: vec![],
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl BatchConfirmation {
+    // merkle tree leaves: hash of every leaf, legacy batch的leaf就是digest
+    pub fn leaf_hashes(&self) -> Vec<[u8; 32]> {
+        if self.legacy {
+            return self.nodes.iter().map(|leaf| leaf.digest).collect();
+        }
+        self.nodes.iter().map(ConfirmationLeaf::hash).collect()
+    }
+
+    // 签名的对象: sha256(index(u32 be) || root || prev_hash)
+    pub fn header_hash(&self) -> [u8; 32] {
+        header_hash(self.index, &self.root, &self.prev_hash)
+    }
+
+    // 已签名的batch里digest的confirmation, 没有签名或者没有这个digest时返回None
+    pub fn confirmation(&self, digest: &[u8; 32]) -> Option<Confirmation> {
+        let signature = self.signature.clone()?;
+        let leaf_index = self.nodes.iter().position(|x| x.digest == *digest)?;
+        let merkle_tree = MerkleTree::<Sha256>::from_leaves(&self.leaf_hashes());
+        let leaf = &self.nodes[leaf_index];
+
+        let proof = Proof {
+            proof_bytes: merkle_tree.proof(&[leaf_index]).to_bytes(),
+            leaf_index,
+            leaf_count: self.nodes.len(),
+            leaf_digest: *digest,
+            blob_size: leaf.size,
+            timestamp: leaf.timestamp,
+            storage_canisters: leaf.canisters.clone(),
+        };
+
+        Some(Confirmation {
+            root: self.root,
+            proof,
+            signature,
+            batch_index: self.index,
+            prev_hash: self.prev_hash,
+            legacy: self.legacy,
+        })
+    }
+
+    // 旧版本的batch补全index和封装时间
+    // - 已签名的batch: 封装时间记为upgrade的时间(now), 一个live time以后过期
+    // - 已满但没有签名的batch: 按新的leaf重新计算root, 由timer重新签名
+    // - 当前的batch: 只补全index, 继续放入新的digest
+    pub fn migrate(mut self, batch_index: u32, current_index: u32, now: u64) -> Self {
+        self.index = batch_index;
+        if self.signature.is_some() {
+            self.timestamp = now;
+        } else if batch_index < current_index {
+            let merkle_tree = MerkleTree::<Sha256>::from_leaves(&self.leaf_hashes());
+            self.root = merkle_tree.root().unwrap_or_default();
+            self.timestamp = now;
+        }
+        self
+    }
+}
+
+pub fn header_hash(index: u32, root: &[u8; 32], prev_hash: &[u8; 32]) -> [u8; 32] {
+    let mut bytes = Vec::with_capacity(4 + 32 + 32);
+    bytes.extend_from_slice(&index.to_be_bytes());
+    bytes.extend_from_slice(root);
+    bytes.extend_from_slice(prev_hash);
+    Sha256::hash(&bytes)
 }
 
 impl Default for BatchConfirmation {
@@ -97,10 +307,58 @@ impl Default for BatchConfirmation {
             signature: None,
             root: [0x00u8; 32],
             nodes: Vec::with_capacity(CONFIRMATION_BATCH_SIZE),
+            index: 0,
+            prev_hash: [0x00u8; 32],
+            timestamp: 0,
+            signing_cycles: 0,
+            legacy: false,
         }
     }
 }
 
+// 签名相关的cycles统计
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CyclesStatus {
+    pub balance: u128,              // 当前的cycles余额
+    pub reserve: u128,              // config里的cycles_reserve
+    pub sign_cost: u128,            // 每次sign_with_ecdsa附带的cycles
+    pub paused: bool,               // balance < reserve + sign_cost 时暂停封装batch
+    pub total_signing_cycles: u128, // 现存batch签名花费的cycles总和
+    pub signed_batches: u64,        // 现存的已签名batch数量
+    pub failed_signs: u64,          // 上次upgrade以后签名失败的次数
+    pub unsigned_batches: u64,      // 已封装但还没有签名的batch数量
+}
+
+// heap上的签名统计, post_upgrade时从现存的batch重新构建
+#[derive(Default)]
+pub(crate) struct SigningStats {
+    pub total_cycles: u128,
+    pub signed: u64,
+    pub failed: u64,
+    pub unsigned: BTreeSet<u32>, // 已封装但还没有签名的batch index, timer按顺序重新签名
+}
+
+// 每个digest被哪些storage canister上报过, 达到replica quorum以后才会进入batch
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DigestReports {
+    pub size: usize,
+    pub timestamp: u128,
+    pub canisters: Vec<Principal>, // reporting storage canisters, in report order
+    pub first_report: u64,         // 第一次上报的时间(nanos), 超过live time还没进入batch就删除
+}
+
+impl Storable for DigestReports {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Default)]
 pub(crate) struct BatchIndex(pub u32); // u32 => 136 years, 1 block / second
 
@@ -119,12 +377,33 @@ impl Storable for BatchIndex {
     };
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+// 新增的字段必须是Option: upgrade前保存的config里没有这个字段, 解码为None
+// None的字段通过下面的方法取默认值
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub confirmation_batch_size: usize,
-    pub confirmation_live_time: u32,
+    pub confirmation_live_time: u32, // secs since the batch is sealed
     pub da_canisters: HashSet<Principal>,
-    pub owner: Principal, // who can change confirmation config
+    pub replica_quorum: Option<usize>, // how many da canisters must report a digest before confirming it
+    pub subscribers: Option<HashSet<Principal>>, // canisters notified by on_batch_confirmed
+    pub archive_canister: Option<Principal>, // expired batches are archived here before removal
+    pub cycles_reserve: Option<u128>, // batches are not sealed while the balance is below reserve + sign cost
+    pub owner: Principal,             // who can change confirmation config
+}
+
+impl Config {
+    // None => 每个replica都要上报
+    pub fn replica_quorum(&self) -> usize {
+        self.replica_quorum.unwrap_or(REPLICA_NUM)
+    }
+
+    pub fn subscribers(&self) -> HashSet<Principal> {
+        self.subscribers.clone().unwrap_or_default()
+    }
+
+    pub fn cycles_reserve(&self) -> u128 {
+        self.cycles_reserve.unwrap_or(CYCLES_RESERVE)
+    }
 }
 
 impl Default for Config {
@@ -137,9 +416,13 @@ impl Default for Config {
         });
 
         Self {
-            confirmation_live_time: CONFIRMATION_LIVE_TIME, // 7 days in batch number
+            confirmation_live_time: CONFIRMATION_LIVE_TIME, // 7 days in secs
             confirmation_batch_size: CONFIRMATION_BATCH_SIZE, // 12 blobs per confirmation
             da_canisters,
+            replica_quorum: Some(REPLICA_NUM), // every replica must report the digest
+            subscribers: Some(HashSet::new()),
+            archive_canister: None,
+            cycles_reserve: Some(CYCLES_RESERVE),
             owner: Principal::from_text(
                 "ytoqu-ey42w-sb2ul-m7xgn-oc7xo-i4btp-kuxjc-b6pt4-dwdzu-kfqs4-nae",
             )
@@ -147,3 +430,96 @@ impl Default for Config {
         }
     }
 }
+
+// config保存在stable memory里, upgrade以后恢复
+// 解码失败时trap, upgrade回滚, 不能用默认的config覆盖owner和da_canisters
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("decode stable config failed: {}", e)))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rs_merkle::MerkleProof;
+    use secp256k1::ecdsa::Signature;
+    use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+    #[test]
+    fn test_migrate_signed_legacy_batch() {
+        // upgrade前签名的batch: leaf = digest, 签名的是root
+        let nodes = (0..5u8).map(|i| [i; 32]).collect::<Vec<_>>();
+        let root = MerkleTree::<Sha256>::from_leaves(&nodes).root().unwrap();
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let signature = secp
+            .sign_ecdsa(&Message::from_digest(root), &secret_key)
+            .serialize_compact();
+        let legacy = LegacyBatchConfirmation {
+            signature: Some(hex::encode(signature)),
+            root,
+            nodes,
+        };
+
+        let bytes = Encode!(&legacy).unwrap();
+        let batch = BatchConfirmation::from_bytes(Cow::Owned(bytes)).migrate(3, 5, 42);
+        assert!(batch.legacy);
+        assert_eq!((batch.index, batch.timestamp, batch.root), (3, 42, root));
+
+        let confirmation = batch.confirmation(&[2; 32]).unwrap();
+        assert!(confirmation.legacy);
+        assert_eq!(confirmation.batch_index, 3);
+
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        let signature =
+            Signature::from_compact(&hex::decode(&confirmation.signature).unwrap()).unwrap();
+        secp.verify_ecdsa(
+            &Message::from_digest(confirmation.root),
+            &signature,
+            &public_key,
+        )
+        .unwrap();
+
+        let proof = &confirmation.proof;
+        let merkle_proof = MerkleProof::<Sha256>::try_from(proof.proof_bytes.as_slice()).unwrap();
+        assert!(merkle_proof.verify(
+            confirmation.root,
+            &[proof.leaf_index],
+            &[proof.leaf_digest],
+            proof.leaf_count,
+        ));
+    }
+
+    #[test]
+    fn test_migrate_unsigned_legacy_batch() {
+        // 没有签名的batch按新的leaf重新计算root, 由timer重新签名
+        let legacy = LegacyBatchConfirmation {
+            signature: None,
+            root: [0; 32],
+            nodes: (0..5u8).map(|i| [i; 32]).collect(),
+        };
+
+        let bytes = Encode!(&legacy).unwrap();
+        let batch = BatchConfirmation::from_bytes(Cow::Owned(bytes)).migrate(3, 5, 42);
+        assert!(!batch.legacy);
+        assert_eq!(batch.timestamp, 42);
+        let leaves = batch
+            .nodes
+            .iter()
+            .map(ConfirmationLeaf::hash)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batch.root,
+            MerkleTree::<Sha256>::from_leaves(&leaves).root().unwrap()
+        );
+        assert!(batch.confirmation(&[2; 32]).is_none());
+    }
+}
@@ -18,21 +18,60 @@
 //! - 通过batch index获取到BatchConfirmation结构体
 //! - 通过tree和index生成proof，然后生成confirmation
 //!
+//! ## replica quorum
+//! - storage canister存完blob以后上报digest, 记录在digest => DigestReports的map
+//! - 上报的storage canister数量达到replica_quorum以后, digest才进入当前batch
+//! - 进入batch时的上报集合会写进merkle leaf
+//! - 还没有进入batch的上报按第一次上报的时间排队(REPORT_QUEUE), 超过live time没有达到quorum的按顺序删除
+//!
+//! ## 导出batch
+//! - get_batch / get_batch_by_digest 返回整个BatchConfirmation
+//! - get_confirmations 对每个batch返回一个multi-proof
+//!
+//! ## certified confirmation
+//! - 签名后的batch root放进 batch index => root 的tree, tree的root hash作为certified data
+//! - get_certified_confirmation 返回 IC certificate 和 batch root 的 witness, query结果可以被验证
+//!
+//! ## 订阅
+//! - batch签名成功以后, 通知config里的subscribers: on_batch_confirmed(batch_index, root, signature)
+//! - 单向通知(notify), 不等待subscriber的返回
+//!
+//! ## hash chain
+//! - batch封装时计算merkle root, 并记录上一个batch的header hash: prev_hash
+//! - 链头(最新封装的batch的header hash)保存在stable memory里, 上一个batch被删除了也能链接
+//! - 只有第一个batch的prev_hash是0
+//! - header hash = sha256(index || root || prev_hash), threshold signature签的是header hash
+//! - 删除过期的batch以后, 剩下的第一个batch的prev_hash仍然承诺了之前的batch
+//!
+//! ## cycles
+//! - 每次sign_with_ecdsa附带 SIGN_WITH_ECDSA_CYCLES, 退回的部分不计入, 实际花费记在batch的signing_cycles
+//! - 余额低于 cycles_reserve + SIGN_WITH_ECDSA_CYCLES 时暂停封装: 满了的batch不封装, 新的digest留在SEALING_QUEUE里(Pending)
+//! - timer定时检查, 余额恢复以后封装满了的batch, 重新签名失败的batch, 然后把暂停期间的digest放进batch
+//! - cycles_status 返回余额, reserve, 是否暂停和签名花费的统计
+//!
 //! ## 删除confirmation
-//! - 每次生成1个confirmation，就说明可能有一个confirmation过期了,如果过期了就删除过期的confirmation
+//! - timer定时检查, 封装时间超过confirmation_live_time的batch都过期了
+//! - 配置了archive canister时, 过期的batch先批量发送到archive canister再删除
 
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
 
 use candid::{candid_method, Principal};
+use ic_cdk::api::call::{call_with_payment128, msg_cycles_refunded128};
+use ic_cdk::api::{canister_balance128, data_certificate, set_certified_data, time};
 use ic_cdk::{caller, print, spawn};
-use ic_cdk_macros::{query, update};
+use ic_cdk_macros::{init, post_upgrade, query, update};
+use ic_certified_map::{AsHashTree, Hash, RbTree};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
 use rs_merkle::algorithms::Sha256;
 use rs_merkle::MerkleTree;
+use serde::Serialize;
 
 use crate::confirmation::{
-    BatchConfirmation, BatchIndex, Config, Confirmation, ConfirmationStatus, Proof,
+    BatchConfirmation, BatchIndex, BatchProof, BlobInfo, CertifiedConfirmation, ChainHead, Config,
+    ConfirmationLeaf, ConfirmationStatus, CyclesStatus, DigestReports, SigningStats,
 };
 use crate::signature::{
     mgmt_canister_id, ECDSAPublicKey, ECDSAPublicKeyReply, EcdsaKeyIds, SignWithECDSA,
@@ -64,20 +103,73 @@ thread_local! {
         MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(1)))
     ));
 
+    // hex encode digest => storage canisters which reported the digest
+    static DIGEST_REPORTS: RefCell<StableBTreeMap<String, DigestReports, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(2)))
+    ));
+
+    // CONFIRMATION_CONFIG的stable备份, update_config时写入, post_upgrade时恢复
+    static STABLE_CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(3))),
+        Config::default(),
+    ).unwrap());
+
+    // 最新封装的batch的header hash, 封装下一个batch时作为prev_hash
+    static CHAIN_HEAD: RefCell<StableCell<ChainHead, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(4))),
+        ChainHead::default(),
+    ).unwrap());
+
+    // (first_report, digest) => (), 还没有进入batch的上报, prune时按第一次上报的时间顺序删除过期的
+    static REPORT_QUEUE: RefCell<StableBTreeMap<(u64, [u8; 32]), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(5)))
+    ));
+
+    // (first_report, digest) => (), 暂停封装时达到quorum但没有进入batch的digest, 恢复封装时按顺序放进batch
+    static SEALING_QUEUE: RefCell<StableBTreeMap<(u64, [u8; 32]), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(6)))
+    ));
+
     static PUBLIC_KEY: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+
+    // batch index(big endian) => root of the signed batches, root hash => certified data
+    // 在heap上, post_upgrade时从BATCH_CONFIRMATION重新构建
+    static CERTIFIED_ROOTS: RefCell<RbTree<Vec<u8>, Hash>> = RefCell::new(RbTree::new());
+
+    // 签名花费的cycles统计, post_upgrade时重新构建
+    static SIGNING_STATS: RefCell<SigningStats> = RefCell::new(SigningStats::default());
+
+    // 正在签名的batch index, timer重试时跳过
+    static SIGNING_BATCHES: RefCell<HashSet<u32>> = RefCell::new(HashSet::new());
+
+    // 正在等archive canister返回, timer的下一次prune跳过
+    static PRUNING: RefCell<bool> = const { RefCell::new(false) };
 }
 
 const CURRENT_INDEX_KEY: &str = "current_index";
+// 每次prune最多处理的batch数量, 也是发送给archive canister的批量大小
+const PRUNE_BATCH_LIMIT: usize = 100;
+// 每次prune最多删除的过期上报数量, 也是每次恢复封装最多放进batch的digest数量
+const REPORT_BATCH_LIMIT: usize = 1000;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const RESUME_SEALING_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// more than 26_153_846_153,
+// which specified in :https://internetcomputer.org/docs/current/references/t-ecdsa-how-it-works/#api
+const SIGN_WITH_ECDSA_CYCLES: u128 = 27_000_000_000;
 
 // 获取confirmation
 // - 通过key获取到batch index
 // - 通过batch index获取到BatchConfirmation结构体
 // - 通过tree和index生成proof，然后生成confirmation
 #[query(name = "get_confirmation")]
-#[candid_method]
+#[candid_method(query)]
 fn get_confirmation(digest: [u8; 32]) -> ConfirmationStatus {
     let hex_digest = hex::encode(digest);
     match INDEX_MAP.with_borrow(|m| m.get(&hex_digest)) {
+        // reported, but the replica quorum is not met yet
+        None if DIGEST_REPORTS.with_borrow(|m| m.contains_key(&hex_digest)) => {
+            ConfirmationStatus::Pending
+        }
         None => ConfirmationStatus::Invalid,
         Some(BatchIndex(batch_index)) => {
             let batch_confirmation = BATCH_CONFIRMATION
@@ -87,31 +179,159 @@ fn get_confirmation(digest: [u8; 32]) -> ConfirmationStatus {
                 return ConfirmationStatus::Pending;
             }
 
-            let merkle_tree = MerkleTree::<Sha256>::from_leaves(&batch_confirmation.nodes);
+            match batch_confirmation.confirmation(&digest) {
+                None => ConfirmationStatus::Invalid,
+                Some(confirmation) => ConfirmationStatus::Confirmed(confirmation),
+            }
+        }
+    }
+}
+
+// 获取confirmation, 附带IC certificate和batch root的witness
+#[query(name = "get_certified_confirmation")]
+#[candid_method(query)]
+fn get_certified_confirmation(digest: [u8; 32]) -> CertifiedConfirmation {
+    let status = get_confirmation(digest);
+
+    let batch_index = match status {
+        ConfirmationStatus::Confirmed(_) => INDEX_MAP
+            .with_borrow(|m| m.get(&hex::encode(digest)))
+            .map(|BatchIndex(index)| index),
+        _ => None,
+    };
+
+    let (certificate, witness) = match batch_index {
+        Some(index) => (
+            data_certificate().unwrap_or_default(),
+            batch_root_witness(index),
+        ),
+        None => (vec![], vec![]),
+    };
 
-            let leaf_index = match batch_confirmation.nodes.iter().position(|&x| x == digest) {
-                None => return ConfirmationStatus::Invalid,
-                Some(index) => index,
-            };
+    CertifiedConfirmation {
+        status,
+        batch_index,
+        certificate,
+        witness,
+    }
+}
 
-            let root = batch_confirmation.root;
-            let proof_bytes = merkle_tree.proof(&[leaf_index]).to_bytes();
+#[query(name = "get_batch")]
+#[candid_method(query)]
+fn get_batch(batch_index: u32) -> Option<BatchConfirmation> {
+    BATCH_CONFIRMATION.with_borrow(|m| m.get(&batch_index))
+}
 
-            let proof = Proof {
-                proof_bytes,
-                leaf_index,
-                leaf_digest: digest,
-            };
+#[query(name = "get_batch_by_digest")]
+#[candid_method(query)]
+fn get_batch_by_digest(digest: [u8; 32]) -> Option<BatchConfirmation> {
+    let BatchIndex(batch_index) = INDEX_MAP.with_borrow(|m| m.get(&hex::encode(digest)))?;
+    get_batch(batch_index)
+}
 
-            let confirmation = Confirmation {
-                root,
-                proof,
-                signature: batch_confirmation.signature.unwrap(),
-            };
+// 批量获取confirmation
+// - digest按batch分组, 每个已签名的batch返回一个multi-proof
+// - 不存在或者还没签名的digest不返回
+#[query(name = "get_confirmations")]
+#[candid_method(query)]
+fn get_confirmations(digests: Vec<[u8; 32]>) -> Vec<BatchProof> {
+    let mut batches: BTreeMap<u32, Vec<[u8; 32]>> = BTreeMap::new();
+    INDEX_MAP.with_borrow(|m| {
+        for digest in digests {
+            if let Some(BatchIndex(batch_index)) = m.get(&hex::encode(digest)) {
+                batches.entry(batch_index).or_default().push(digest);
+            }
+        }
+    });
 
-            ConfirmationStatus::Confirmed(confirmation)
+    let mut proofs = Vec::with_capacity(batches.len());
+    for (batch_index, digests) in batches {
+        let batch_confirmation = match get_batch(batch_index) {
+            Some(batch_confirmation) => batch_confirmation,
+            None => continue,
+        };
+        let signature = match batch_confirmation.signature.clone() {
+            Some(signature) => signature,
+            None => continue,
+        };
+
+        let mut leaf_indices = batch_confirmation
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, leaf)| digests.contains(&leaf.digest))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        leaf_indices.sort_unstable();
+        if leaf_indices.is_empty() {
+            continue;
         }
+
+        let merkle_tree = MerkleTree::<Sha256>::from_leaves(&batch_confirmation.leaf_hashes());
+        let proof_bytes = merkle_tree.proof(&leaf_indices).to_bytes();
+        let leaves = leaf_indices
+            .iter()
+            .map(|&index| batch_confirmation.nodes[index].clone())
+            .collect();
+
+        proofs.push(BatchProof {
+            batch_index,
+            root: batch_confirmation.root,
+            signature,
+            proof_bytes,
+            leaf_indices,
+            leaves,
+            leaf_count: batch_confirmation.nodes.len(),
+            prev_hash: batch_confirmation.prev_hash,
+            legacy: batch_confirmation.legacy,
+        });
     }
+
+    proofs
+}
+
+// 最新封装的batch的header hash
+#[query(name = "get_chain_head")]
+#[candid_method(query)]
+fn get_chain_head() -> Option<ChainHead> {
+    let head = CHAIN_HEAD.with_borrow(|c| c.get().clone());
+    (head.batch_index != 0).then_some(head)
+}
+
+// 当前的config, update_config之前先读出来再修改
+#[query(name = "get_config")]
+#[candid_method(query)]
+fn get_config() -> Config {
+    CONFIRMATION_CONFIG.with_borrow(|c| c.clone())
+}
+
+#[query(name = "get_archive_canister")]
+#[candid_method(query)]
+fn get_archive_canister() -> Option<Principal> {
+    CONFIRMATION_CONFIG.with_borrow(|c| c.archive_canister)
+}
+
+// 获取上报过digest的storage canisters
+#[query(name = "get_digest_reports")]
+#[candid_method(query)]
+fn get_digest_reports(digest: [u8; 32]) -> Option<DigestReports> {
+    DIGEST_REPORTS.with_borrow(|m| m.get(&hex::encode(digest)))
+}
+
+// cycles余额和签名花费
+#[query(name = "cycles_status")]
+#[candid_method(query)]
+fn cycles_status() -> CyclesStatus {
+    SIGNING_STATS.with_borrow(|stats| CyclesStatus {
+        balance: canister_balance128(),
+        reserve: CONFIRMATION_CONFIG.with_borrow(|c| c.cycles_reserve()),
+        sign_cost: SIGN_WITH_ECDSA_CYCLES,
+        paused: sealing_paused(),
+        total_signing_cycles: stats.total_cycles,
+        signed_batches: stats.signed,
+        failed_signs: stats.failed,
+        unsigned_batches: stats.unsigned.len() as u64,
+    })
 }
 
 #[query(name = "get_public_key")]
@@ -121,64 +341,129 @@ fn public_key() -> Vec<u8> {
 }
 
 // 更新本地的digest
-// digest: hex encoded digest
+// blob_info: digest, size, storing canister, timestamp => merkle leaf
 // 最后如果判断需要签名，就签名
-// 删除过期的confirmation
 #[update(name = "insert_digest")]
 #[candid_method]
-async fn insert_digest(digest: [u8; 32]) {
+async fn insert_digest(blob_info: BlobInfo) {
     assert!(check_updater(caller()), "only updater can insert digest");
+    assert_eq!(
+        blob_info.canister,
+        caller(),
+        "storing canister must be the caller"
+    );
+    let digest = blob_info.digest;
     let digest_hex = hex::encode(digest);
 
-    let mut confirmation_update_info = None;
+    // blob超过live time的上报不再记录, 它所在的batch可能已经删除了
+    let expiry = blob_info
+        .timestamp
+        .saturating_add(live_time_nanos() as u128);
+    if expiry <= time() as u128 {
+        print(format!(
+            "ignore expired digest report: digest: {}, blob info: {:?}",
+            digest_hex, blob_info
+        ));
+        return;
+    }
 
-    INDEX_MAP.with(|index_map| {
-        if index_map.borrow().contains_key(&digest_hex) {
-            return;
-        }
+    // 记录上报的storage canister, 没有达到quorum就先不进入batch
+    let reports = match record_digest_report(&digest_hex, &blob_info) {
+        Some(reports) => reports,
+        None => return,
+    };
+    if reports.canisters.len() < CONFIRMATION_CONFIG.with_borrow(|c| c.replica_quorum()) {
+        return;
+    }
 
-        // default current index = 1, compatible with live time
-        let mut current_index = 1;
+    if let Some((batch_index, confirmation)) = append_leaf(digest, reports) {
+        spawn(update_signature(batch_index, confirmation));
+    }
+}
 
-        let _index = index_map.borrow().get(&CURRENT_INDEX_KEY.to_string());
-        if let Some(BatchIndex(index)) = _index {
-            current_index = index;
-        } else {
-            index_map
-                .borrow_mut()
-                .insert(CURRENT_INDEX_KEY.to_string(), BatchIndex(current_index));
-        }
+// digest放进当前batch, batch满了就封装, 返回需要签名的batch
+// 暂停封装时当前batch满了以后不再放入, digest放进SEALING_QUEUE等timer恢复
+fn append_leaf(digest: [u8; 32], reports: DigestReports) -> Option<(u32, BatchConfirmation)> {
+    let digest_hex = hex::encode(digest);
+    if INDEX_MAP.with_borrow(|m| m.contains_key(&digest_hex)) {
+        return None;
+    }
 
-        index_map
-            .borrow_mut()
-            .insert(digest_hex.clone(), BatchIndex(current_index));
-
-        BATCH_CONFIRMATION.with(|batch_map| {
-            let mut batch_confirmation = batch_map.borrow().get(&current_index).unwrap_or_default();
-            batch_confirmation.nodes.push(digest);
-            batch_map
-                .borrow_mut()
-                .insert(current_index, batch_confirmation.clone());
-
-            if batch_confirmation.nodes.len()
-                % CONFIRMATION_CONFIG.with_borrow(|config| config.confirmation_batch_size)
-                == 0
-            {
-                prune_expired_confirmation(current_index);
-
-                let new_current_index = current_index + 1;
-                index_map
-                    .borrow_mut()
-                    .insert(CURRENT_INDEX_KEY.to_string(), BatchIndex(new_current_index));
-
-                confirmation_update_info = Some((current_index, batch_confirmation));
-            }
+    let batch_size = CONFIRMATION_CONFIG.with_borrow(|c| c.confirmation_batch_size);
+    let current_index = current_index();
+    let mut batch_confirmation = BATCH_CONFIRMATION
+        .with_borrow(|m| m.get(&current_index))
+        .unwrap_or_else(|| BatchConfirmation {
+            index: current_index,
+            ..Default::default()
         });
+    let queue_key = (reports.first_report, digest);
+    if batch_confirmation.nodes.len() >= batch_size {
+        SEALING_QUEUE.with_borrow_mut(|q| q.insert(queue_key, ()));
+        return None;
+    }
+
+    INDEX_MAP.with_borrow_mut(|m| m.insert(digest_hex, BatchIndex(current_index)));
+    REPORT_QUEUE.with_borrow_mut(|q| q.remove(&queue_key));
+    SEALING_QUEUE.with_borrow_mut(|q| q.remove(&queue_key));
+    batch_confirmation.nodes.push(ConfirmationLeaf {
+        digest,
+        size: reports.size,
+        timestamp: reports.timestamp,
+        canisters: reports.canisters,
     });
+    BATCH_CONFIRMATION.with_borrow_mut(|m| m.insert(current_index, batch_confirmation.clone()));
 
-    if let Some((batch_index, confirmation)) = confirmation_update_info {
-        spawn(update_signature(batch_index, confirmation));
+    if batch_confirmation.nodes.len() >= batch_size && !sealing_paused() {
+        return Some(seal_batch(batch_confirmation));
     }
+    None
+}
+
+// default current index = 1, compatible with live time
+fn current_index() -> u32 {
+    INDEX_MAP.with_borrow_mut(|m| match m.get(&CURRENT_INDEX_KEY.to_string()) {
+        Some(BatchIndex(index)) => index,
+        None => {
+            m.insert(CURRENT_INDEX_KEY.to_string(), BatchIndex(1));
+            1
+        }
+    })
+}
+
+// seal the batch: merkle root & link to the chain head, then move to the next batch
+// 链头保存在CHAIN_HEAD里, 上一个batch已经被删除也不会断链
+fn seal_batch(mut batch_confirmation: BatchConfirmation) -> (u32, BatchConfirmation) {
+    let batch_index = batch_confirmation.index;
+    let merkle_tree = MerkleTree::<Sha256>::from_leaves(&batch_confirmation.leaf_hashes());
+    batch_confirmation.root = merkle_tree.root().unwrap();
+    batch_confirmation.prev_hash = CHAIN_HEAD.with_borrow(|c| c.get().header_hash);
+    batch_confirmation.timestamp = time();
+    BATCH_CONFIRMATION.with_borrow_mut(|m| m.insert(batch_index, batch_confirmation.clone()));
+    SIGNING_STATS.with_borrow_mut(|stats| stats.unsigned.insert(batch_index));
+    CHAIN_HEAD.with_borrow_mut(|c| {
+        c.set(ChainHead {
+            batch_index,
+            header_hash: batch_confirmation.header_hash(),
+        })
+        .unwrap()
+    });
+
+    INDEX_MAP.with_borrow_mut(|m| {
+        m.insert(CURRENT_INDEX_KEY.to_string(), BatchIndex(batch_index + 1));
+    });
+
+    (batch_index, batch_confirmation)
+}
+
+// 余额不够 reserve + 一次签名 时暂停封装
+fn sealing_paused() -> bool {
+    let reserve = CONFIRMATION_CONFIG.with_borrow(|c| c.cycles_reserve());
+    canister_balance128() < reserve.saturating_add(SIGN_WITH_ECDSA_CYCLES)
+}
+
+fn is_unsigned(batch_confirmation: &BatchConfirmation) -> bool {
+    batch_confirmation.timestamp != 0 && batch_confirmation.signature.is_none()
 }
 
 #[update(name = "update_config")]
@@ -188,6 +473,7 @@ fn update_config(config: Config) {
         check_owner(caller()),
         "only owner can update signature batch size"
     );
+    STABLE_CONFIG.with_borrow_mut(|c| c.set(config.clone()).unwrap());
     CONFIRMATION_CONFIG.with_borrow_mut(|c| *c = config);
 }
 
@@ -205,6 +491,79 @@ async fn init() {
     }
 }
 
+#[init]
+fn canister_init() {
+    start_timers();
+}
+
+// config从stable memory恢复; public key重新获取
+// certified tree和签名统计在heap上, upgrade以后重新构建; timer也需要重新设置
+#[post_upgrade]
+fn post_upgrade() {
+    let config = STABLE_CONFIG.with_borrow(|c| c.get().clone());
+    CONFIRMATION_CONFIG.with_borrow_mut(|c| *c = config);
+    migrate_legacy_batches();
+    init_chain_head();
+
+    start_timers();
+    ic_cdk_timers::set_timer(Duration::ZERO, || spawn(init()));
+
+    BATCH_CONFIRMATION.with_borrow(|m| {
+        CERTIFIED_ROOTS.with_borrow_mut(|tree| {
+            SIGNING_STATS.with_borrow_mut(|stats| {
+                for (batch_index, batch_confirmation) in m.iter() {
+                    if batch_confirmation.signature.is_some() {
+                        tree.insert(batch_key(batch_index), batch_confirmation.root);
+                        stats.total_cycles += batch_confirmation.signing_cycles;
+                        stats.signed += 1;
+                    } else if is_unsigned(&batch_confirmation) {
+                        stats.unsigned.insert(batch_index);
+                    }
+                }
+            });
+            set_certified_data(&tree.root_hash());
+        })
+    });
+}
+
+// 旧版本的batch(index != key)补全index和封装时间, 写回新的格式
+// 已签名的batch标记为legacy, 继续按旧的格式(leaf = digest, 签名的是root)生成proof
+fn migrate_legacy_batches() {
+    let current_index = current_index();
+    let legacy_batches = BATCH_CONFIRMATION.with_borrow(|m| {
+        m.iter()
+            .filter(|(batch_index, batch_confirmation)| batch_confirmation.index != *batch_index)
+            .collect::<Vec<_>>()
+    });
+
+    for (batch_index, batch_confirmation) in legacy_batches {
+        let batch_confirmation = batch_confirmation.migrate(batch_index, current_index, time());
+        print(format!("migrate legacy batch: {:?}", batch_confirmation));
+        BATCH_CONFIRMATION.with_borrow_mut(|m| m.insert(batch_index, batch_confirmation));
+    }
+}
+
+// 没有CHAIN_HEAD的旧版本: 从最新封装的batch初始化
+fn init_chain_head() {
+    if CHAIN_HEAD.with_borrow(|c| c.get().batch_index) != 0 {
+        return;
+    }
+
+    let head = current_index()
+        .checked_sub(1)
+        .and_then(get_batch)
+        .filter(|batch_confirmation| batch_confirmation.timestamp != 0);
+    if let Some(head) = head {
+        CHAIN_HEAD.with_borrow_mut(|c| {
+            c.set(ChainHead {
+                batch_index: head.index,
+                header_hash: head.header_hash(),
+            })
+            .unwrap()
+        });
+    }
+}
+
 candid::export_service!();
 #[test]
 fn export_candid() {
@@ -226,91 +585,342 @@ pub async fn init_public_key() -> Result<Vec<u8>, String> {
     Ok(res.public_key)
 }
 
-// 1. update merkle root
-// 2. sign merkle root([u8;32])
-// 3. update signature
+// 1. sign header hash([u8;32]), root & prev_hash are set when the batch is sealed
+// 2. update signature
+// 3. 失败的batch由timer重试
 async fn update_signature(batch_index: u32, batch_confirmation: BatchConfirmation) {
+    // 同一个batch只签一次
+    if !SIGNING_BATCHES.with_borrow_mut(|s| s.insert(batch_index)) {
+        return;
+    }
+
     // 获取batch confirmation
     let mut confirmation = batch_confirmation;
-
-    // 构建merkle tree
-    let merkle_tree = MerkleTree::<Sha256>::from_leaves(&confirmation.nodes);
-
-    // 获取 merkle root & 更新merkle root
-    let root = merkle_tree.root().unwrap();
-    confirmation.root = root;
-
-    // sign merkle root
-    match sign(root.to_vec()).await {
-        Ok(SignatureReply { signature_hex }) => {
-            confirmation.signature = Some(signature_hex);
+    let root = confirmation.root;
+
+    // sign header hash
+    match sign(confirmation.header_hash().to_vec()).await {
+        Ok(SignatureReply {
+            signature_hex,
+            cycles,
+        }) => {
+            confirmation.signature = Some(signature_hex.clone());
+            confirmation.signing_cycles = cycles;
+            SIGNING_STATS.with_borrow_mut(|stats| {
+                stats.total_cycles += cycles;
+                stats.signed += 1;
+                stats.unsigned.remove(&batch_index);
+            });
             // 更新batch confirmation & insert
             print(format!("update signature for batch: {:?}", confirmation));
             BATCH_CONFIRMATION.with_borrow_mut(|c| c.insert(batch_index, confirmation));
+            certify_batch_root(batch_index, root);
+            notify_subscribers(batch_index, root, signature_hex);
+        }
+        Err(e) => {
+            SIGNING_STATS.with_borrow_mut(|stats| stats.failed += 1);
+            print(format!(
+                "sign failed: batch: {:?}, error: {}",
+                confirmation, e
+            ))
         }
-        Err(e) => print(format!(
-            "sign failed: batch: {:?}, error: {}",
-            confirmation, e
-        )),
     };
+
+    SIGNING_BATCHES.with_borrow_mut(|s| s.remove(&batch_index));
+}
+
+// on_batch_confirmed(batch_index, root, signature)
+fn notify_subscribers(batch_index: u32, root: [u8; 32], signature_hex: String) {
+    let subscribers = CONFIRMATION_CONFIG.with_borrow(|c| c.subscribers());
+    for subscriber in subscribers {
+        if let Err(e) = ic_cdk::notify(
+            subscriber,
+            "on_batch_confirmed",
+            (batch_index, root, signature_hex.clone()),
+        ) {
+            print(format!(
+                "notify subscriber failed: subscriber: {}, batch: {}, error: {:?}",
+                subscriber.to_text(),
+                batch_index,
+                e
+            ));
+        }
+    }
 }
 
 // sign [u8;32]
+// 余额不够时不发起签名, 返回实际花费的cycles
 async fn sign(hash: Vec<u8>) -> Result<SignatureReply, String> {
+    if sealing_paused() {
+        return Err(format!(
+            "insufficient cycles: balance: {}",
+            canister_balance128()
+        ));
+    }
+
     let request = SignWithECDSA {
         message_hash: hash,
         derivation_path: vec![],
         key_id: EcdsaKeyIds::ProductionKey1.to_key_id(),
     };
 
-    let (response,): (SignWithECDSAReply,) = ic_cdk::api::call::call_with_payment(
+    let res: Result<(SignWithECDSAReply,), _> = call_with_payment128(
         mgmt_canister_id(),
         "sign_with_ecdsa",
         (request,),
-        // more than 26_153_846_153,
-        // which specified in :https://internetcomputer.org/docs/current/references/t-ecdsa-how-it-works/#api
-        27_000_000_000,
+        SIGN_WITH_ECDSA_CYCLES,
     )
-    .await
-    .map_err(|e| format!("sign_with_ecdsa failed {}", e.1))?;
+    .await;
+    let cycles = SIGN_WITH_ECDSA_CYCLES.saturating_sub(msg_cycles_refunded128());
+    let (response,) = res.map_err(|e| format!("sign_with_ecdsa failed {}", e.1))?;
 
     Ok(SignatureReply {
         signature_hex: hex::encode(response.signature),
+        cycles,
     })
 }
 
-fn prune_expired_confirmation(current_batch_index: u32) {
-    let confirmation_live_time = CONFIRMATION_CONFIG.with_borrow(|c| c.confirmation_live_time);
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(PRUNE_INTERVAL, || spawn(prune_expired_confirmation()));
+    ic_cdk_timers::set_timer_interval(RESUME_SEALING_INTERVAL, resume_sealing);
+}
 
-    if current_batch_index <= confirmation_live_time {
+// 余额恢复以后:
+// 1. 重新签名已封装但没有签名的batch
+// 2. 封装满了的当前batch
+// 3. 暂停期间达到quorum但没有进入batch的digest放进batch
+fn resume_sealing() {
+    if sealing_paused() {
         return;
     }
 
-    let expired_batch_index = current_batch_index - confirmation_live_time;
-
-    BATCH_CONFIRMATION.with_borrow_mut(|c| {
-        let expired_node_keys = c
-            .get(&expired_batch_index)
-            .unwrap()
-            .nodes
-            .iter()
-            .map(hex::encode)
-            .collect::<Vec<_>>();
+    let unsigned_batches = SIGNING_STATS.with_borrow(|stats| stats.unsigned.clone());
+    for batch_index in unsigned_batches {
+        if let Some(batch_confirmation) = get_batch(batch_index) {
+            spawn(update_signature(batch_index, batch_confirmation));
+        }
+    }
 
-        let expired_confirmation = c.remove(&expired_batch_index);
+    let batch_size = CONFIRMATION_CONFIG.with_borrow(|c| c.confirmation_batch_size);
+    if let Some(batch_confirmation) = get_batch(current_index()) {
+        if batch_confirmation.nodes.len() >= batch_size {
+            let (batch_index, batch_confirmation) = seal_batch(batch_confirmation);
+            spawn(update_signature(batch_index, batch_confirmation));
+        }
+    }
 
-        // remove nodes index
-        INDEX_MAP.with_borrow_mut(|m| {
-            for key in expired_node_keys.iter() {
-                m.remove(key);
+    // 按第一次上报的顺序, 每次最多 REPORT_BATCH_LIMIT 个, 剩下的等下一次timer
+    let pending_digests = SEALING_QUEUE.with_borrow(|q| {
+        q.iter()
+            .map(|(queue_key, _)| queue_key)
+            .take(REPORT_BATCH_LIMIT)
+            .collect::<Vec<_>>()
+    });
+    for (first_report, digest) in pending_digests {
+        let digest_reports = match DIGEST_REPORTS.with_borrow(|m| m.get(&hex::encode(digest))) {
+            Some(digest_reports) => digest_reports,
+            None => {
+                SEALING_QUEUE.with_borrow_mut(|q| q.remove(&(first_report, digest)));
+                continue;
             }
-        });
+        };
+        if let Some((batch_index, batch_confirmation)) = append_leaf(digest, digest_reports) {
+            spawn(update_signature(batch_index, batch_confirmation));
+        }
+        if sealing_paused() {
+            break;
+        }
+    }
+}
+
+// 删除过期的confirmation
+// - 第一次上报超过confirmation_live_time还没有进入batch的digest(没有达到quorum), 删除上报记录
+// - 按index顺序扫描, 封装时间超过confirmation_live_time的batch都过期, 每次最多处理 PRUNE_BATCH_LIMIT 个
+// - 没有封装的batch(timestamp == 0)不会过期
+// - 配置了archive canister时, 先批量发送到archive canister, 只删除archive canister确认保存了的batch
+// - 上一次prune还在等archive canister时跳过
+async fn prune_expired_confirmation() {
+    if PRUNING.with_borrow(|p| *p) {
+        return;
+    }
 
+    let archive_canister = CONFIRMATION_CONFIG.with_borrow(|c| c.archive_canister);
+    let live_time = live_time_nanos();
+    let now = time();
+
+    prune_expired_reports(now, live_time);
+
+    let expired_batches = BATCH_CONFIRMATION.with_borrow(|c| {
+        c.iter()
+            .map(|(_, batch_confirmation)| batch_confirmation)
+            .take_while(|batch_confirmation| {
+                batch_confirmation.timestamp != 0
+                    && batch_confirmation.timestamp.saturating_add(live_time) <= now
+            })
+            .take(PRUNE_BATCH_LIMIT)
+            .collect::<Vec<_>>()
+    });
+
+    if expired_batches.is_empty() {
+        return;
+    }
+
+    let removable = match archive_canister {
+        Some(archive_canister) => {
+            PRUNING.with_borrow_mut(|p| *p = true);
+            let archived = archive_batches(archive_canister, &expired_batches).await;
+            PRUNING.with_borrow_mut(|p| *p = false);
+            archived
+        }
+        None => expired_batches.iter().map(|b| b.index).collect(),
+    };
+
+    for expired_confirmation in expired_batches
+        .into_iter()
+        .filter(|batch_confirmation| removable.contains(&batch_confirmation.index))
+    {
+        remove_batch(&expired_confirmation);
         print(format!(
             "remove expired confirmation: {:?}",
             expired_confirmation
         ));
+    }
+}
+
+// 返回archive canister确认保存了的batch index, 失败时返回空, 下一次prune再重试
+async fn archive_batches(
+    archive_canister: Principal,
+    batches: &[BatchConfirmation],
+) -> HashSet<u32> {
+    let res: Result<(Result<Vec<u32>, String>,), _> =
+        ic_cdk::call(archive_canister, "append_batches", (batches,)).await;
+    match res {
+        Ok((Ok(archived),)) => archived.into_iter().collect(),
+        Ok((Err(e),)) | Err((_, e)) => {
+            print(format!(
+                "archive expired confirmation failed: archive: {}, error: {}",
+                archive_canister.to_text(),
+                e
+            ));
+            HashSet::new()
+        }
+    }
+}
+
+// REPORT_QUEUE按第一次上报的时间排序, 只取开头过期的部分, 每次最多 REPORT_BATCH_LIMIT 个
+fn prune_expired_reports(now: u64, live_time: u64) {
+    let expired_reports = REPORT_QUEUE.with_borrow(|q| {
+        q.iter()
+            .map(|(queue_key, _)| queue_key)
+            .take_while(|(first_report, _)| first_report.saturating_add(live_time) <= now)
+            .take(REPORT_BATCH_LIMIT)
+            .collect::<Vec<_>>()
+    });
+
+    for queue_key in expired_reports {
+        let digest_hex = hex::encode(queue_key.1);
+        print(format!("remove expired digest reports: {}", digest_hex));
+        REPORT_QUEUE.with_borrow_mut(|q| q.remove(&queue_key));
+        SEALING_QUEUE.with_borrow_mut(|q| q.remove(&queue_key));
+        DIGEST_REPORTS.with_borrow_mut(|m| m.remove(&digest_hex));
+    }
+}
+
+fn live_time_nanos() -> u64 {
+    let confirmation_live_time = CONFIRMATION_CONFIG.with_borrow(|c| c.confirmation_live_time);
+    Duration::from_secs(confirmation_live_time as u64).as_nanos() as u64
+}
+
+fn remove_batch(batch_confirmation: &BatchConfirmation) {
+    BATCH_CONFIRMATION.with_borrow_mut(|c| c.remove(&batch_confirmation.index));
+    uncertify_batch_root(batch_confirmation.index);
+    SIGNING_STATS.with_borrow_mut(|stats| stats.unsigned.remove(&batch_confirmation.index));
+    if batch_confirmation.signature.is_some() {
+        SIGNING_STATS.with_borrow_mut(|stats| {
+            stats.total_cycles = stats
+                .total_cycles
+                .saturating_sub(batch_confirmation.signing_cycles);
+            stats.signed = stats.signed.saturating_sub(1);
+        });
+    }
+
+    // remove nodes index & reports
+    let expired_node_keys = batch_confirmation
+        .nodes
+        .iter()
+        .map(|leaf| hex::encode(leaf.digest))
+        .collect::<Vec<_>>();
+    INDEX_MAP.with_borrow_mut(|m| {
+        for key in expired_node_keys.iter() {
+            m.remove(key);
+        }
     });
+    DIGEST_REPORTS.with_borrow_mut(|m| {
+        for key in expired_node_keys.iter() {
+            m.remove(key);
+        }
+    });
+}
+
+fn batch_key(batch_index: u32) -> Vec<u8> {
+    batch_index.to_be_bytes().to_vec()
+}
+
+fn certify_batch_root(batch_index: u32, root: [u8; 32]) {
+    CERTIFIED_ROOTS.with_borrow_mut(|tree| {
+        tree.insert(batch_key(batch_index), root);
+        set_certified_data(&tree.root_hash());
+    });
+}
+
+fn uncertify_batch_root(batch_index: u32) {
+    CERTIFIED_ROOTS.with_borrow_mut(|tree| {
+        tree.delete(&batch_key(batch_index));
+        set_certified_data(&tree.root_hash());
+    });
+}
+
+// cbor encoded witness of batch index => root
+fn batch_root_witness(batch_index: u32) -> Vec<u8> {
+    CERTIFIED_ROOTS.with_borrow(|tree| {
+        let witness = tree.witness(&batch_key(batch_index));
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        witness.serialize(&mut serializer).unwrap();
+        serializer.into_inner()
+    })
+}
+
+// 记录digest的上报者, 返回当前的上报集合
+// size或者timestamp和之前的上报不一致时, 忽略这次上报
+fn record_digest_report(digest_hex: &String, blob_info: &BlobInfo) -> Option<DigestReports> {
+    DIGEST_REPORTS.with_borrow_mut(|m| {
+        let mut reports = m.get(digest_hex).unwrap_or_else(|| {
+            let first_report = time();
+            REPORT_QUEUE.with_borrow_mut(|q| q.insert((first_report, blob_info.digest), ()));
+            DigestReports {
+                size: blob_info.size,
+                timestamp: blob_info.timestamp,
+                canisters: Vec::new(),
+                first_report,
+            }
+        });
+
+        if reports.size != blob_info.size || reports.timestamp != blob_info.timestamp {
+            print(format!(
+                "digest report mismatch: digest: {}, reports: {:?}, blob info: {:?}",
+                digest_hex, reports, blob_info
+            ));
+            return None;
+        }
+
+        if !reports.canisters.contains(&blob_info.canister) {
+            reports.canisters.push(blob_info.canister);
+            m.insert(digest_hex.clone(), reports.clone());
+        }
+
+        Some(reports)
+    })
 }
 
 // 只有自己的canister才能写进来key
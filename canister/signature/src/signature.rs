@@ -18,6 +18,7 @@ pub struct PublicKeyReply {
 #[derive(CandidType, Serialize, Debug)]
 pub struct SignatureReply {
     pub signature_hex: String,
+    pub cycles: u128, // cycles actually charged, the rest is refunded
 }
 
 pub type CanisterId = Principal;
@@ -3,11 +3,11 @@
 //! time heap
 //! signature
 
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::print;
 use serde::Serialize;
 
-use crate::{BLOBS, DACONFIG};
+use crate::{BLOBS, DACONFIG, RECEIVED_CHUNKS};
 
 pub struct BlobData(pub Vec<u8>);
 
@@ -30,42 +30,93 @@ pub struct BlobChunk {
     pub data: Vec<u8>,
 }
 
+// 通知signature canister的blob信息, 会被hash成confirmation的merkle leaf
+#[derive(Deserialize, Serialize, CandidType, Debug, Clone)]
+pub struct BlobInfo {
+    /// Sha256 digest of the blob.
+    pub digest: [u8; 32],
+
+    /// Total blob size in bytes.
+    pub size: usize,
+
+    /// The storage canister holding the blob.
+    pub canister: Principal,
+
+    /// Time since epoch in nanos.
+    pub timestamp: u128,
+}
+
+// 给placement policy用的负载信息
+#[derive(Deserialize, Serialize, CandidType, Debug, Clone)]
+pub struct StorageStats {
+    /// Number of blobs stored.
+    pub blob_count: u64,
+
+    /// Stable memory in use, in bytes.
+    pub stable_memory_bytes: u64,
+
+    /// Blobs kept before the oldest ones are removed.
+    pub threshold: u32,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Default)]
 pub struct Blob {
     pub data: Vec<u8>,
     pub next: Option<usize>, // next index
 }
 
-// 1. 第一次上传，则创建一个空的vec，大小为total
-// 2. 之后的上传，将chunk append到vec中
+// 1. 第一次上传，则创建一个空的vec，大小为total, 以及收到chunk的bitmap
+// 2. 之后的上传，将chunk写到index对应的位置, chunk可以乱序到达
+// 3. 所有chunk都收到以后返回true, 并删除bitmap
 pub fn insert_to_store_map(
     hexed_digest: &String,
     index: usize,
     total_size: usize,
     data: &[u8],
 ) -> bool {
+    let chunk_size = DACONFIG.with_borrow(|c| c.chunk_size);
+
     BLOBS.with(|map| {
         let mut value = map
             .borrow()
             .get(hexed_digest)
             .unwrap_or_else(|| vec![0; total_size]);
 
-        let chunk_size = DACONFIG.with_borrow(|c| c.chunk_size);
-
         let start = index * chunk_size;
         let end = (start + chunk_size).min(total_size);
 
         value[start..end].copy_from_slice(data);
 
         let _ = map.borrow_mut().insert(hexed_digest.to_string(), value);
-        end == total_size
+    });
+
+    RECEIVED_CHUNKS.with_borrow_mut(|m| {
+        let chunk_num = total_size.div_ceil(chunk_size).max(1);
+        let mut bitmap = m
+            .get(hexed_digest)
+            .unwrap_or_else(|| vec![0; chunk_num.div_ceil(8)]);
+        bitmap[index / 8] |= 1 << (index % 8);
+
+        let complete = (0..chunk_num).all(|i| bitmap[i / 8] & (1 << (i % 8)) != 0);
+        if complete {
+            m.remove(hexed_digest);
+        } else {
+            m.insert(hexed_digest.to_string(), bitmap);
+        }
+        complete
     })
 }
 
+// 还有chunk没收到
+pub fn is_receiving(hexed_digest: &String) -> bool {
+    RECEIVED_CHUNKS.with_borrow(|m| m.contains_key(hexed_digest))
+}
+
 pub fn remove_expired_blob_from_map(digest: [u8; 32]) {
     BLOBS.with(|map| {
         let hex_digest = hex::encode(digest);
         let v = map.borrow_mut().remove(&hex_digest);
+        RECEIVED_CHUNKS.with_borrow_mut(|m| m.remove(&hex_digest));
         if v.is_some() {
             print(format!("remove expired blob of digest: {}", hex_digest));
         }
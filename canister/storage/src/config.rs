@@ -1,5 +1,8 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashSet;
 
 const SIGNATURE_CANISTER: &str = "r34pn-oaaaa-aaaak-qinga-cai";
@@ -9,10 +12,11 @@ const QUERY_RESPONSE_SIZE: usize = 2621440; // 2.5 * 1024 * 1024 = 2.5 MB
 const CANISTER_THRESHOLD: u32 = 30240;
 const CHUNK_SIZE: usize = 1 << 20; // 1M
 
+// 新增的字段必须是Option: upgrade前保存的config里没有这个字段, 解码为None
 #[derive(Deserialize, Serialize, CandidType, Clone)]
 pub struct Config {
     pub owner: HashSet<Principal>, // who can upload to da canister
-    pub signature_canister: Principal,
+    pub signature_canisters: Vec<Principal>, // notified on every saved blob
     pub chunk_size: usize,
     pub query_response_size: usize,
     pub canister_storage_threshold: u32,
@@ -21,7 +25,7 @@ pub struct Config {
 impl Default for Config {
     fn default() -> Self {
         Self {
-            signature_canister: Principal::from_text(SIGNATURE_CANISTER).unwrap(),
+            signature_canisters: vec![Principal::from_text(SIGNATURE_CANISTER).unwrap()],
             chunk_size: CHUNK_SIZE,
             query_response_size: QUERY_RESPONSE_SIZE,
             owner: HashSet::from_iter(vec![
@@ -32,3 +36,18 @@ impl Default for Config {
         }
     }
 }
+
+// config保存在stable memory里, upgrade以后恢复
+// 解码失败时trap, upgrade回滚, 不能用默认的config覆盖owner和signature_canisters
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("decode stable config failed: {}", e)))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
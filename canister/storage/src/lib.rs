@@ -1,13 +1,13 @@
 extern crate core;
 
-use crate::blob::{remove_expired_blob_from_map, Blob, BlobChunk};
+use crate::blob::{remove_expired_blob_from_map, Blob, BlobChunk, BlobInfo, StorageStats};
 use crate::config::Config;
-use crate::time_heap::{insert_to_time_heap, BlobId};
+use crate::time_heap::{get_blob_timestamp, insert_to_time_heap, BlobId};
 use candid::{candid_method, Principal};
 use ic_cdk::{caller, print, spawn};
 use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableMinHeap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableMinHeap};
 
 use sha2::{Digest, Sha256};
 use std::cell::RefCell;
@@ -42,6 +42,21 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         ).unwrap()
     );
+
+    // 上传中的blob收到了哪些chunk, 全部收到后删除
+    static RECEIVED_CHUNKS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    // DACONFIG的stable备份, update_config时写入, post_upgrade时恢复
+    static STABLE_CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            Config::default(),
+        ).unwrap()
+    );
 }
 
 // Retrieves the value associated with the given key if it exists.
@@ -94,6 +109,14 @@ fn get_blob_with_index(digest: [u8; 32], index: usize) -> Blob {
     blob
 }
 
+// 完整保存的blob才算存在, 上传中的不算; 空blob的get_blob也是vec![], 靠这个区分
+#[query(name = "contains_blob")]
+#[candid_method(query)]
+fn contains_blob(digest: [u8; 32]) -> bool {
+    let hexed_digest = hex::encode(digest);
+    blob_exist(&hexed_digest) && !blob::is_receiving(&hexed_digest)
+}
+
 // Inserts an entry into the map
 #[update(name = "save_blob")]
 #[candid_method]
@@ -102,6 +125,15 @@ async fn save_blob(chunk: BlobChunk) -> Result<(), String> {
 
     let hexed_digest = hex::encode(chunk.digest);
 
+    // 已经完整保存的blob, 重传的chunk直接忽略
+    // upgrade前上传中的blob没有bitmap, digest不一致的按上传中处理, 重新收齐所有chunk
+    if blob_exist(&hexed_digest)
+        && !blob::is_receiving(&hexed_digest)
+        && check_digest(&hexed_digest, &chunk.digest)
+    {
+        return Ok(());
+    }
+
     // 1. insert into time heap
     //    新的blob到了，检查是否有expired，如果有就remove
     if !blob_exist(&hexed_digest) {
@@ -121,6 +153,7 @@ async fn save_blob(chunk: BlobChunk) -> Result<(), String> {
             BLOBS.with_borrow_mut(|m| {
                 m.remove(&hexed_digest);
             });
+            RECEIVED_CHUNKS.with_borrow_mut(|m| m.remove(&hexed_digest));
             return Err(format!(
                 "storage canister: digest not match: chunk index: {}, {}",
                 chunk.index, hexed_digest
@@ -129,7 +162,12 @@ async fn save_blob(chunk: BlobChunk) -> Result<(), String> {
             // 5. 如果match，再放入stable tree，并且spawn confirmation
             print(format!("saved blob, digest: {:?}", hexed_digest));
             // 3. notify signature canister to generate confirmation
-            spawn(notify_generate_confirmation(chunk.digest));
+            notify_signature_canisters(BlobInfo {
+                digest: chunk.digest,
+                size: chunk.total,
+                canister: ic_cdk::id(),
+                timestamp: chunk.timestamp,
+            });
         }
     };
 
@@ -139,29 +177,78 @@ async fn save_blob(chunk: BlobChunk) -> Result<(), String> {
 #[update(name = "notify_generate_confirmation")]
 #[candid_method]
 async fn notify_generate_confirmation(digest: [u8; 32]) {
-    if !BLOBS.with_borrow(|b| b.contains_key(&hex::encode(digest))) {
-        return;
+    let size = match BLOBS.with_borrow(|b| b.get(&hex::encode(digest))) {
+        Some(data) => data.len(),
+        None => return,
+    };
+
+    let timestamp = match get_blob_timestamp(digest) {
+        Some(timestamp) => timestamp,
+        None => return,
+    };
+
+    notify_signature_canisters(BlobInfo {
+        digest,
+        size,
+        canister: ic_cdk::id(),
+        timestamp,
+    })
+}
+
+// 每个signature canister单独通知, 一个失败不影响其他
+fn notify_signature_canisters(blob_info: BlobInfo) {
+    for signature_canister in DACONFIG.with_borrow(|c| c.signature_canisters.clone()) {
+        spawn(notify_signature_canister(
+            signature_canister,
+            blob_info.clone(),
+        ));
     }
+}
 
-    match ic_cdk::call(
-        DACONFIG.with_borrow(|c| c.signature_canister),
-        "insert_digest",
-        (digest,),
-    )
-    .await
-    {
+async fn notify_signature_canister(signature_canister: Principal, blob_info: BlobInfo) {
+    match ic_cdk::call(signature_canister, "insert_digest", (blob_info,)).await {
         Ok(()) => {}
         Err(e) => {
-            print(format!("save_blob call signature_canister error: {:?}", e));
+            print(format!(
+                "save_blob call signature_canister {} error: {:?}",
+                signature_canister.to_text(),
+                e
+            ));
         }
     }
 }
 
+// cycles余额, 给cycle monitor用
+#[query(name = "cycles")]
+#[candid_method(query)]
+fn cycles() -> u128 {
+    ic_cdk::api::canister_balance128()
+}
+
+// blob数量和stable memory用量, 给placement policy用
+#[query(name = "stats")]
+#[candid_method(query)]
+fn stats() -> StorageStats {
+    StorageStats {
+        blob_count: BLOBS.with_borrow(|m| m.len()),
+        stable_memory_bytes: ic_cdk::api::stable::stable_size() * 65536,
+        threshold: DACONFIG.with_borrow(|c| c.canister_storage_threshold),
+    }
+}
+
 #[update(name = "update_config")]
 #[candid_method]
 fn update_config(config: Config) {
     assert!(check_caller(caller()), "only owner can change da config");
 
+    STABLE_CONFIG.with_borrow_mut(|c| c.set(config.clone()).unwrap());
+    DACONFIG.with_borrow_mut(|c| *c = config);
+}
+
+// 恢复upgrade之前的config
+#[post_upgrade]
+fn post_upgrade() {
+    let config = STABLE_CONFIG.with_borrow(|c| c.get().clone());
     DACONFIG.with_borrow_mut(|c| *c = config);
 }
 
@@ -69,3 +69,12 @@ pub fn insert_to_time_heap(digest: [u8; 32], timestamp: u128) -> Option<BlobId>
         }
     })
 }
+
+// 通过digest找到blob的timestamp
+pub fn get_blob_timestamp(digest: [u8; 32]) -> Option<u128> {
+    TIMEHEAP.with_borrow(|heap| {
+        heap.iter()
+            .find(|blob_id| blob_id.digest == digest)
+            .map(|blob_id| blob_id.timestamp)
+    })
+}
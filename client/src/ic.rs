@@ -1,18 +1,39 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::ops::Div;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::bail;
-use candid::{Decode, Encode, Nat, Principal};
-use cycles_minting_canister::{NotifyCreateCanister, NotifyError, SubnetSelection};
+use async_trait::async_trait;
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use cycles_minting_canister::{NotifyCreateCanister, NotifyError, NotifyTopUp, SubnetSelection};
+use ic_agent::identity::BasicIdentity;
 use ic_agent::Agent;
 use ic_management_canister_types::{CanisterSettingsArgsBuilder, LogVisibility};
 use ic_types::{CanisterId, PrincipalId, SubnetId};
-use icp_ledger::{AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs};
+use icda_core::cycle_monitor::CyclesTopUp;
+use icp_ledger::{
+    AccountIdentifier, Memo, Subaccount, TimeStamp, Tokens, TransferArgs, TransferError,
+};
 use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::TransferError;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
 
 pub const LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
 pub const CMC: &str = "rkp4c-7iaaa-aaaaa-aaaca-cai";
+pub const IC_URL: &str = "https://ic0.app";
+
+// CMC memos, see the cycles minting canister spec
+const CREATE_CANISTER_MEMO: Memo = Memo(1095062083); // "CREA"
+const TOP_UP_MEMO: Memo = Memo(1347768404); // "TPUP", 0x50555054
+const TRANSFER_FEE: Tokens = Tokens::from_e8s(10000);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+// a top-up transfer whose outcome is unknown is sent again with the same args,
+// the ledger deduplicates it by created_at_time
+const TRANSFER_RETRIES: usize = 3;
+const TRANSFER_RETRY_DELAY: Duration = Duration::from_secs(2);
 
 pub const SUBNETS: [&str; 9] = [
     "nl6hn-ja4yw-wvmpy-3z2jx-ymc34-pisx3-3cp5z-3oj4a-qzzny-jbsv3-4qe",
@@ -58,16 +79,25 @@ impl LedgerAgent {
     /// Transfers number of tokens from the account (caller, from_subaccount) to the account (to_principal, to_subaccount).
     pub async fn transfer(&self, args: TransferArgs) -> anyhow::Result<u64> {
         println!("begin transfer");
+        match self.try_transfer(&args).await? {
+            Ok(nat) => Ok(nat),
+            Err(err) => bail!("Transfer failed: {:?}", err),
+        }
+    }
+
+    /// Sends the transfer once. The inner error is a rejection by the ledger, no token moved;
+    /// the outer one means the call failed and the transfer may or may not have happened.
+    pub async fn try_transfer(
+        &self,
+        args: &TransferArgs,
+    ) -> anyhow::Result<Result<u64, TransferError>> {
         let res = self
             .agent
             .update(&self.ledger, "transfer")
-            .with_arg(Encode!(&args)?)
+            .with_arg(Encode!(args)?)
             .call_and_wait()
             .await?;
-        match Decode!(&res, Result<u64, TransferError>)? {
-            Ok(nat) => Ok(nat),
-            Err(err) => bail!("Transfer failed: {:?}", err),
-        }
+        Ok(Decode!(&res, Result<u64, TransferError>)?)
     }
 }
 
@@ -106,6 +136,132 @@ impl CmcAgent {
             }
         }
     }
+
+    /// Mints cycles for the canister from the ICP transferred in `block_index`.
+    pub async fn notify_top_up(&self, arg: NotifyTopUp) -> anyhow::Result<u128> {
+        match self.try_notify_top_up(&arg).await? {
+            Ok(cycles) => Ok(cycles),
+            Err(e) => {
+                bail!("{}", e)
+            }
+        }
+    }
+
+    /// Sends the notification once, the inner error is the answer of the CMC.
+    pub async fn try_notify_top_up(
+        &self,
+        arg: &NotifyTopUp,
+    ) -> anyhow::Result<Result<u128, NotifyError>> {
+        debug!("begin notify top up");
+        let ic_res = self
+            .agent
+            .update(&self.cmc, "notify_top_up")
+            .with_arg(Encode!(arg)?)
+            .call_and_wait()
+            .await?;
+
+        let res = Decode!(&ic_res, Result<u128, NotifyError>)?;
+        debug!("Notify CMC Result: {:?}", res);
+        Ok(res)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum InstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    #[serde(rename = "upgrade")]
+    Upgrade(Option<UpgradeOptions>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default)]
+pub struct UpgradeOptions {
+    pub skip_pre_upgrade: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct InstallCodeArgs {
+    mode: InstallMode,
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    arg: Vec<u8>,
+}
+
+// only the settings we change, the others are left as they are
+#[derive(CandidType, Deserialize)]
+struct CanisterSettings {
+    controllers: Option<Vec<Principal>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct UpdateSettingsArgs {
+    canister_id: Principal,
+    settings: CanisterSettings,
+}
+
+/// Calls to the management canister, the caller must be a controller of the canister.
+#[derive(Clone)]
+pub struct ManagementAgent {
+    pub agent: Agent,
+}
+
+impl ManagementAgent {
+    pub fn new(agent: Agent) -> Self {
+        Self { agent }
+    }
+
+    pub async fn install_code(
+        &self,
+        canister_id: Principal,
+        mode: InstallMode,
+        wasm_module: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let args = InstallCodeArgs {
+            mode,
+            canister_id,
+            wasm_module,
+            arg,
+        };
+        self.agent
+            .update(&Principal::management_canister(), "install_code")
+            .with_effective_canister_id(canister_id)
+            .with_arg(Encode!(&args)?)
+            .call_and_wait()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_controllers(
+        &self,
+        canister_id: Principal,
+        controllers: Vec<Principal>,
+    ) -> anyhow::Result<()> {
+        let args = UpdateSettingsArgs {
+            canister_id,
+            settings: CanisterSettings {
+                controllers: Some(controllers),
+            },
+        };
+        self.agent
+            .update(&Principal::management_canister(), "update_settings")
+            .with_effective_canister_id(canister_id)
+            .with_arg(Encode!(&args)?)
+            .call_and_wait()
+            .await?;
+        Ok(())
+    }
+}
+
+pub fn new_agent(pem_path: &str) -> anyhow::Result<Agent> {
+    let identity = BasicIdentity::from_pem_file(pem_path)?;
+    let agent = Agent::builder()
+        .with_identity(identity)
+        .with_url(IC_URL)
+        .build()?;
+    Ok(agent)
 }
 
 pub async fn get_account_balance(agent: Agent, ledger: LedgerAgent) -> anyhow::Result<()> {
@@ -168,8 +324,8 @@ pub async fn transfer_to_cmc(
     cmc_id: PrincipalId,
     to_subaccount: Subaccount,
 ) -> anyhow::Result<u64> {
-    let memo = Memo(1095062083);
-    let fee = Tokens::from_e8s(10000);
+    let memo = CREATE_CANISTER_MEMO;
+    let fee = TRANSFER_FEE;
     let amount = Tokens::from_e8s(10_000_000);
     let to = AccountIdentifier::new(cmc_id, Some(to_subaccount)).to_address();
 
@@ -188,6 +344,234 @@ pub async fn transfer_to_cmc(
     Ok(block_index)
 }
 
+/// Converts `amount` ICP into cycles for `canister_id`:
+/// transfer to the CMC subaccount of the canister with the top-up memo, then notify the CMC.
+pub async fn top_up_canister(
+    cmc_agent: &CmcAgent,
+    ledger_agent: &LedgerAgent,
+    canister_id: Principal,
+    amount: Tokens,
+) -> anyhow::Result<u128> {
+    let block_index = transfer_top_up(cmc_agent, ledger_agent, canister_id, amount).await?;
+    notify_top_up(cmc_agent, canister_id, block_index).await
+}
+
+/// Why a top-up transfer did not go through.
+#[derive(Debug)]
+pub enum TopUpTransferError {
+    /// The ledger rejected the transfer, no token moved.
+    Rejected(TransferError),
+    /// No retry got an answer from the ledger, the transfer may have happened.
+    Unknown(anyhow::Error),
+}
+
+impl fmt::Display for TopUpTransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(e) => write!(f, "top up transfer rejected: {:?}", e),
+            Self::Unknown(e) => write!(f, "top up transfer outcome unknown: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for TopUpTransferError {}
+
+/// Transfers `amount` ICP to the CMC subaccount of `canister_id`, returns the block index.
+/// Failed calls are sent again with the same args, the ledger returns the block of the
+/// first one instead of transferring twice.
+pub async fn transfer_top_up(
+    cmc_agent: &CmcAgent,
+    ledger_agent: &LedgerAgent,
+    canister_id: Principal,
+    amount: Tokens,
+) -> Result<u64, TopUpTransferError> {
+    let cmc_id = PrincipalId::from(cmc_agent.cmc);
+    let to_subaccount = Subaccount::from(&PrincipalId::from(canister_id));
+    let to = AccountIdentifier::new(cmc_id, Some(to_subaccount)).to_address();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let transfer_args = TransferArgs {
+        from_subaccount: None,
+        to,
+        amount,
+        fee: TRANSFER_FEE,
+        created_at_time: Some(TimeStamp::from_nanos_since_unix_epoch(now.as_nanos() as u64)),
+        memo: TOP_UP_MEMO,
+    };
+
+    let mut attempt = 1;
+    loop {
+        match ledger_agent.try_transfer(&transfer_args).await {
+            Ok(Ok(block_index))
+            | Ok(Err(TransferError::TxDuplicate {
+                duplicate_of: block_index,
+            })) => {
+                info!(
+                    "top up transfer: canister: {}, block index: {}",
+                    canister_id, block_index
+                );
+                return Ok(block_index);
+            }
+            Ok(Err(e)) => return Err(TopUpTransferError::Rejected(e)),
+            Err(e) if attempt >= TRANSFER_RETRIES => return Err(TopUpTransferError::Unknown(e)),
+            Err(e) => {
+                warn!(
+                    "top up transfer: canister: {}, attempt: {}, error: {:#}, retry after {:?}",
+                    canister_id, attempt, e, TRANSFER_RETRY_DELAY
+                );
+                attempt += 1;
+                tokio::time::sleep(TRANSFER_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Mints cycles for `canister_id` from the top-up transfer at `block_index`,
+/// can be retried with the same block index.
+pub async fn notify_top_up(
+    cmc_agent: &CmcAgent,
+    canister_id: Principal,
+    block_index: u64,
+) -> anyhow::Result<u128> {
+    let arg = NotifyTopUp {
+        block_index,
+        canister_id: CanisterId::unchecked_from_principal(PrincipalId::from(canister_id)),
+    };
+    cmc_agent.notify_top_up(arg).await
+}
+
+/// Top-up policy of the cycle monitor: every canister below `min_cycles` gets
+/// `top_up_e8s` ICP, and at most `max_e8s_per_day` ICP is spent in 24 hours.
+pub struct CmcTopUp {
+    cmc_agent: CmcAgent,
+    ledger_agent: LedgerAgent,
+    pub min_cycles: u128,
+    pub top_up_e8s: u64,
+    pub max_e8s_per_day: u64,
+    // (start of the current 24h window, e8s spent in it)
+    spent: Mutex<(Instant, u64)>,
+    // canister => block index of a transfer whose notify failed, notified again before a new transfer
+    pending: Mutex<HashMap<Principal, u64>>,
+}
+
+impl CmcTopUp {
+    pub fn new(agent: Agent, min_cycles: u128, top_up_e8s: u64, max_e8s_per_day: u64) -> Self {
+        Self {
+            cmc_agent: CmcAgent::new(agent.clone()),
+            ledger_agent: LedgerAgent::new(agent),
+            min_cycles,
+            top_up_e8s,
+            max_e8s_per_day,
+            spent: Mutex::new((Instant::now(), 0)),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // notify the CMC of the transfer at `block_index`, it stays pending until the CMC gives
+    // a definite answer: minted, or refunded / invalid, in which case it can't be minted any more
+    async fn notify_pending(
+        &self,
+        canister_id: Principal,
+        block_index: u64,
+    ) -> anyhow::Result<u128> {
+        let arg = NotifyTopUp {
+            block_index,
+            canister_id: CanisterId::unchecked_from_principal(PrincipalId::from(canister_id)),
+        };
+        let res = self.cmc_agent.try_notify_top_up(&arg).await;
+        let definite = !matches!(res, Err(_) | Ok(Err(NotifyError::Processing)));
+        if definite {
+            self.pending.lock().await.remove(&canister_id);
+        }
+
+        match res {
+            Ok(Ok(cycles)) => Ok(cycles),
+            Ok(Err(e)) => bail!(
+                "notify top up failed, canister: {}, block index: {}, pending: {}: {}",
+                canister_id,
+                block_index,
+                !definite,
+                e
+            ),
+            Err(e) => Err(e.context(format!(
+                "notify top up failed, canister: {}, block index: {}, pending: true",
+                canister_id, block_index
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl CyclesTopUp for CmcTopUp {
+    async fn top_up(&self, canister_id: Principal, balance: u128) -> anyhow::Result<()> {
+        // the tokens of an earlier transfer are already at the CMC, mint them before paying again
+        let pending = self.pending.lock().await.get(&canister_id).copied();
+        if let Some(block_index) = pending {
+            let cycles = self.notify_pending(canister_id, block_index).await?;
+            info!(
+                "top up canister: {}, pending block index: {}, minted cycles: {}",
+                canister_id, block_index, cycles
+            );
+            return Ok(());
+        }
+
+        if balance >= self.min_cycles {
+            return Ok(());
+        }
+
+        // reserve the budget before the transfer, so concurrent top-ups can't overspend
+        let cost = self.top_up_e8s + TRANSFER_FEE.get_e8s();
+        let window = {
+            let mut spent = self.spent.lock().await;
+            if spent.0.elapsed() >= DAY {
+                *spent = (Instant::now(), 0);
+            }
+            if spent.1 + cost > self.max_e8s_per_day {
+                bail!(
+                    "daily top up budget exhausted: spent: {} e8s, max: {} e8s",
+                    spent.1,
+                    self.max_e8s_per_day
+                );
+            }
+            spent.1 += cost;
+            spent.0
+        };
+
+        // only a rejected transfer gives the reservation back,
+        // the tokens may have moved when the outcome is unknown
+        let block_index = match transfer_top_up(
+            &self.cmc_agent,
+            &self.ledger_agent,
+            canister_id,
+            Tokens::from_e8s(self.top_up_e8s),
+        )
+        .await
+        {
+            Ok(block_index) => block_index,
+            Err(e) => {
+                if let TopUpTransferError::Rejected(_) = e {
+                    let mut spent = self.spent.lock().await;
+                    if spent.0 == window {
+                        spent.1 = spent.1.saturating_sub(cost);
+                    }
+                }
+                return Err(e.into());
+            }
+        };
+
+        // the tokens are already at the CMC, the reservation is kept
+        self.pending.lock().await.insert(canister_id, block_index);
+        let cycles = self.notify_pending(canister_id, block_index).await?;
+        info!(
+            "top up canister: {}, balance: {}, minted cycles: {}",
+            canister_id, balance, cycles
+        );
+        Ok(())
+    }
+}
+
 pub fn sha256(input: &String) -> [u8; 32] {
     use sha2::Digest;
     let mut hasher = sha2::Sha256::new();
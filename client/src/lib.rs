@@ -1,11 +1,22 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
+use anyhow::{anyhow, bail};
+use candid::{Decode, Encode, Principal};
 use futures::future::join_all;
+use ic_agent::Agent;
+use ic_types::{PrincipalId, SubnetId};
 use icda_core::canister_interface::signature::{
-    ConfirmationStatus, SignatureCanisterConfig, VerifyResult,
+    ConfirmationStatus, CyclesStatus, SignatureCanisterConfig, VerifyResult,
 };
-use icda_core::canister_interface::storage::{RoutingInfo, StorageCanisterConfig};
+use icda_core::canister_interface::rr_agent::RoundRobinAgent;
+use icda_core::canister_interface::storage::{
+    RoutingInfo, StorageCanister, StorageCanisterConfig, StorageClient,
+};
+use icda_core::config::{CanisterInfo, IcdaConfig};
 use icda_core::icda::{BlobKey, ICDA};
+use crate::ic::{
+    create_canister_in_specific_subnet, CmcAgent, InstallMode, LedgerAgent, ManagementAgent,
+};
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
 use serde_json::json;
@@ -205,55 +216,71 @@ pub async fn verify_confirmation(key_path: String, da: ICDA) -> anyhow::Result<(
 
     let keys: Vec<BlobKey> = serde_json::from_str(&content).unwrap();
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel(keys.len());
-
-    for bk in keys.iter() {
-        let _tx = tx.clone();
-        let digest = bk.digest;
-        let _da = da.clone();
-        tokio::spawn(async move {
-            let confirmation = _da
-                .signature_canister
-                .get_confirmation(digest)
-                .await
-                .unwrap();
-            let hexed_digest = hex::encode(digest);
-            match _tx.send((hexed_digest, confirmation)).await {
-                Ok(_) => {}
-                Err(e) => error!("send confirmation failed, error: {}", e),
-            }
-        });
+    // 每个signature canister都验证一遍
+    let total = keys.len() * da.signature_canisters.len();
+    let (tx, mut rx) = tokio::sync::mpsc::channel(total.max(1));
+
+    for (sc_index, sc) in da.signature_canisters.iter().enumerate() {
+        for bk in keys.iter() {
+            let _tx = tx.clone();
+            let digest = bk.digest;
+            let _sc = sc.clone();
+            tokio::spawn(async move {
+                let confirmation = _sc.get_confirmation(digest).await.unwrap();
+                let hexed_digest = hex::encode(digest);
+                match _tx.send((sc_index, hexed_digest, confirmation)).await {
+                    Ok(_) => {}
+                    Err(e) => error!("send confirmation failed, error: {}", e),
+                }
+            });
+        }
     }
 
     // receive channel
-    for _ in 0..keys.len() {
-        if let Some((hexed_digest, confirmation)) = rx.recv().await {
+    for _ in 0..total {
+        if let Some((sc_index, hexed_digest, confirmation)) = rx.recv().await {
+            let sc = &da.signature_canisters[sc_index];
+            let cid = sc.canister_id().to_text();
             match confirmation {
                 ConfirmationStatus::Confirmed(confirmation) => {
-                    match da
-                        .signature_canister
-                        .verify_confirmation(&confirmation)
-                        .await
-                    {
-                        VerifyResult::Valid => {
-                            info!("confirmation verified, digest: {}", hexed_digest);
+                    match sc.verify_confirmation(&confirmation).await {
+                        Ok(VerifyResult::Valid) => {
+                            info!(
+                                "confirmation verified, signature canister: {}, digest: {}",
+                                cid, hexed_digest
+                            );
                         }
-                        VerifyResult::InvalidProof => {
-                            error!("confirmation proof is invalid, digest: {}", hexed_digest)
+                        Ok(VerifyResult::InvalidProof) => {
+                            error!(
+                                "confirmation proof is invalid, signature canister: {}, digest: {}",
+                                cid, hexed_digest
+                            )
                         }
-                        VerifyResult::InvalidSignature(err) => {
+                        Ok(VerifyResult::InvalidSignature(err)) => {
                             error!(
-                                "confirmation signature is invalid: {}, digest: {}",
-                                err, hexed_digest
+                                "confirmation signature is invalid: {}, signature canister: {}, digest: {}",
+                                err, cid, hexed_digest
+                            )
+                        }
+                        Err(e) => {
+                            error!(
+                                "failed to verify confirmation: {}, signature canister: {}, digest: {}",
+                                e, cid, hexed_digest
                             )
                         }
                     }
                 }
                 ConfirmationStatus::Pending => {
-                    warn!("confirmation is pending, digest: {}", hexed_digest)
+                    warn!(
+                        "confirmation is pending, signature canister: {}, digest: {}",
+                        cid, hexed_digest
+                    )
                 }
                 ConfirmationStatus::Invalid => {
-                    error!("digest is invalid, digest: {}", hexed_digest)
+                    error!(
+                        "digest is invalid, signature canister: {}, digest: {}",
+                        cid, hexed_digest
+                    )
                 }
             }
         }
@@ -284,11 +311,11 @@ pub async fn init_canister(config_path: String, da: &ICDA) -> anyhow::Result<()>
             match s.update_config(&_config).await {
                 Ok(_) => info!(
                     "update storage canister config success, cid: {}",
-                    s.canister_id
+                    s.canister_id()
                 ),
                 Err(e) => error!(
                     "update storage canister config failed, cid: {}, error: {}",
-                    s.canister_id, e
+                    s.canister_id(), e
                 ),
             }
         });
@@ -296,17 +323,233 @@ pub async fn init_canister(config_path: String, da: &ICDA) -> anyhow::Result<()>
     join_all(tasks).await;
     info!("updated storage canister config");
 
-    let _ = da.signature_canister.init().await;
+    for sc in da.signature_canisters.iter() {
+        let _ = sc.init().await;
 
-    match da
-        .signature_canister
-        .update_config(&signature_canister_config)
-        .await
-    {
-        Ok(_) => info!("update signature config success"),
-        Err(e) => error!("update signature config failed: {}", e),
+        match sc.update_config(&signature_canister_config).await {
+            Ok(_) => info!("update signature config success, cid: {}", sc.canister_id()),
+            Err(e) => error!(
+                "update signature config failed, cid: {}, error: {}",
+                sc.canister_id(), e
+            ),
+        }
+    }
+    info!("signature canisters initialized and updated");
+
+    Ok(())
+}
+
+pub struct ProvisionArgs {
+    pub subnets: Vec<Principal>,
+    pub replicas: usize,
+    pub wasm_path: String,
+    pub controllers: Vec<Principal>,
+    pub output: String,
+}
+
+// 1. create a storage canister on every subnet, install the storage wasm, set controllers
+//    and point its config at the signature canisters of the client config
+// 2. every `replicas` canisters form a collection, the IcdaConfig file is rewritten after
+//    every created canister, so a failed run still records the paid canisters
+// 3. register the new canisters in the da_canisters of every signature canister
+pub async fn provision_canisters(
+    agent: Agent,
+    args: ProvisionArgs,
+    da: &ICDA,
+) -> anyhow::Result<IcdaConfig> {
+    if args.replicas == 0 || args.subnets.len() % args.replicas != 0 {
+        bail!(
+            "provision: {} subnets can't be grouped into collections of {} replicas",
+            args.subnets.len(),
+            args.replicas
+        );
     }
-    info!("signature canister initialized and updated");
 
+    let wasm_module = fs::read(&args.wasm_path).await?;
+    let cmc_agent = CmcAgent::new(agent.clone());
+    let ledger_agent = LedgerAgent::new(agent.clone());
+    let management_agent = ManagementAgent::new(agent.clone());
+
+    let mut controllers = args.controllers.clone();
+    let me = agent.get_principal().map_err(|e| anyhow!(e))?;
+    if !controllers.contains(&me) {
+        controllers.push(me);
+    }
+
+    // the default owners are kept, `me` needs to be one of them to update the config
+    let mut storage_config = StorageCanisterConfig {
+        signature_canisters: da.config.signature_canisters.clone(),
+        query_response_size: da.config.query_response_size,
+        ..Default::default()
+    };
+    storage_config.owner.insert(me);
+    let rr_agent = Arc::new(RoundRobinAgent::from_agent(agent.clone()));
+
+    let provisioned_config = |canisters: &[CanisterInfo]| IcdaConfig {
+        collections: canisters
+            .chunks(args.replicas)
+            .map(|collection| collection.to_vec())
+            .collect(),
+        replica_num: args.replicas,
+        write_quorum: da.config.write_quorum.min(args.replicas),
+        ..da.config.as_ref().clone()
+    };
+
+    let mut canisters = Vec::with_capacity(args.subnets.len());
+    for subnet in args.subnets.iter() {
+        let subnet_id = SubnetId::new(PrincipalId::from(*subnet));
+        let canister_id =
+            create_canister_in_specific_subnet(cmc_agent.clone(), ledger_agent.clone(), subnet_id)
+                .await?;
+        info!(
+            "provision: created canister: {}, subnet: {}",
+            canister_id, subnet
+        );
+        canisters.push(CanisterInfo {
+            canister_id,
+            subnet: Some(*subnet),
+        });
+        fs::write(
+            &args.output,
+            serde_json::to_string_pretty(&provisioned_config(&canisters))?,
+        )
+        .await?;
+
+        management_agent
+            .install_code(
+                canister_id,
+                InstallMode::Install,
+                wasm_module.clone(),
+                Encode!()?,
+            )
+            .await?;
+        info!(
+            "provision: installed storage wasm, canister: {}",
+            canister_id
+        );
+
+        management_agent
+            .set_controllers(canister_id, controllers.clone())
+            .await?;
+        info!(
+            "provision: set controllers, canister: {}, controllers: {:?}",
+            canister_id, controllers
+        );
+
+        StorageCanister::new(canister_id, rr_agent.clone())
+            .update_config(&storage_config)
+            .await?;
+        info!(
+            "provision: updated storage config, canister: {}, signature canisters: {:?}",
+            canister_id, storage_config.signature_canisters
+        );
+    }
+
+    let config = provisioned_config(&canisters);
+    info!("provision: wrote icda config to {}", args.output);
+
+    for sc in da.signature_canisters.iter() {
+        let mut signature_config = sc.get_config().await?;
+        signature_config
+            .da_canisters
+            .extend(canisters.iter().map(|c| c.canister_id));
+        sc.update_config(&signature_config).await?;
+        info!(
+            "provision: registered {} canisters with signature canister: {}",
+            canisters.len(),
+            sc.canister_id()
+        );
+    }
+
+    Ok(config)
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum CanisterKind {
+    Storage,
+    Signature,
+}
+
+// 1. upgrade the canisters one collection at a time
+// 2. after every canister, check its health query before moving on
+// 3. halt on the first failure, the rest keep the old wasm
+pub async fn upgrade_canisters(
+    agent: Agent,
+    kind: CanisterKind,
+    wasm_path: String,
+    collections: Vec<Vec<Principal>>,
+) -> anyhow::Result<()> {
+    let wasm_module = fs::read(&wasm_path).await?;
+    let management_agent = ManagementAgent::new(agent.clone());
+
+    for (index, collection) in collections.iter().enumerate() {
+        for canister_id in collection.iter() {
+            management_agent
+                .install_code(
+                    *canister_id,
+                    InstallMode::Upgrade(None),
+                    wasm_module.clone(),
+                    Encode!()?,
+                )
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "upgrade: collection: {}, canister: {}, install_code failed: {:?}",
+                        index,
+                        canister_id,
+                        e
+                    )
+                })?;
+
+            check_health(&agent, kind, *canister_id)
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "upgrade: collection: {}, canister: {}, health check failed: {:?}",
+                        index,
+                        canister_id,
+                        e
+                    )
+                })?;
+            info!("upgrade: upgraded {:?} canister: {}", kind, canister_id);
+        }
+        info!("upgrade: collection {} upgraded", index);
+    }
+
+    Ok(())
+}
+
+// storage: cycles query, signature: cycles_status query
+async fn check_health(
+    agent: &Agent,
+    kind: CanisterKind,
+    canister_id: Principal,
+) -> anyhow::Result<()> {
+    match kind {
+        CanisterKind::Storage => {
+            let res = agent
+                .query(&canister_id, "cycles")
+                .with_arg(Encode!()?)
+                .call()
+                .await?;
+            let cycles = Decode!(&res, u128)?;
+            info!(
+                "upgrade: storage canister: {}, cycles: {}",
+                canister_id, cycles
+            );
+        }
+        CanisterKind::Signature => {
+            let res = agent
+                .query(&canister_id, "cycles_status")
+                .with_arg(Encode!()?)
+                .call()
+                .await?;
+            let status = Decode!(&res, CyclesStatus)?;
+            info!(
+                "upgrade: signature canister: {}, cycles status: {:?}",
+                canister_id, status
+            );
+        }
+    }
     Ok(())
 }
@@ -4,12 +4,23 @@
 
 extern crate core;
 
+use std::str::FromStr;
+use std::sync::Arc;
+
 use anyhow::Result;
+use candid::Principal;
 use clap::{Parser, Subcommand};
+use icp_ledger::Tokens;
 use tokio::fs;
-use tracing::{info, Level};
+use tracing::{error, info, Level};
 
-use client::{get_from_canister, init_canister, put_to_canister, verify_confirmation};
+use client::ic::{new_agent, top_up_canister, CmcAgent, CmcTopUp, LedgerAgent, SUBNETS};
+use client::{
+    get_from_canister, init_canister, provision_canisters, put_to_canister, upgrade_canisters,
+    verify_confirmation, CanisterKind, ProvisionArgs,
+};
+use icda_core::config::IcdaConfig;
+use icda_core::cycle_monitor::CycleMonitor;
 use icda_core::icda::ICDA;
 
 #[derive(Parser)]
@@ -32,6 +43,14 @@ enum Commands {
     Verify,
     #[command(name = "init")]
     Init(InitConfigPath),
+    #[command(name = "top-up")]
+    TopUp(TopUpArgs),
+    #[command(name = "monitor")]
+    Monitor(MonitorArgs),
+    #[command(name = "provision")]
+    Provision(ProvisionConfig),
+    #[command(name = "upgrade")]
+    Upgrade(UpgradeConfig),
 }
 
 #[derive(serde::Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Parser)]
@@ -40,6 +59,26 @@ struct InitConfigPath {
     path: String,
 }
 
+// convert ICP of the identity into cycles of the canister
+#[derive(Clone, Parser)]
+struct TopUpArgs {
+    #[arg(long, short)]
+    canister: String,
+    #[arg(long, short)]
+    e8s: u64,
+}
+
+// keep every canister above min_tcycles, spending at most max_e8s_per_day
+#[derive(Clone, Parser)]
+struct MonitorArgs {
+    #[arg(long, default_value_t = 2)]
+    min_tcycles: u64,
+    #[arg(long, default_value_t = 10_000_000)] // 0.1 icp
+    top_up_e8s: u64,
+    #[arg(long, default_value_t = 100_000_000)] // 1 icp
+    max_e8s_per_day: u64,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 struct Config {
     identity: IdentityConfig,
@@ -47,6 +86,8 @@ struct Config {
     #[serde(rename = "blobkey")]
     blob_key: BlobKeyConfig,
     mode: Option<Mode>,
+    // canister topology, toml or json, defaults to the compiled-in canisters
+    icda_config: Option<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
@@ -70,6 +111,39 @@ struct BlobKeyConfig {
     path: String,
 }
 
+// create storage canisters, install the wasm and register them with the signature canisters
+#[derive(Clone, Parser)]
+struct ProvisionConfig {
+    /// storage canister wasm
+    #[arg(long, short)]
+    wasm: String,
+    /// subnets to create canisters on, defaults to SUBNETS
+    #[arg(long, value_delimiter = ',')]
+    subnets: Vec<String>,
+    /// canisters per collection
+    #[arg(long, default_value_t = 1)]
+    replicas: usize,
+    /// extra controllers besides the identity
+    #[arg(long, value_delimiter = ',')]
+    controllers: Vec<String>,
+    #[arg(long, short, default_value = "icda.json")]
+    output: String,
+}
+
+// rolling upgrade of storage or signature canisters
+#[derive(Clone, Parser)]
+struct UpgradeConfig {
+    /// new canister wasm
+    #[arg(long, short)]
+    wasm: String,
+    /// storage or signature
+    #[arg(long, short, value_enum, default_value_t = CanisterKind::Storage)]
+    kind: CanisterKind,
+    /// icda config written by provision, defaults to the collections of the client config
+    #[arg(long)]
+    collections: Option<String>,
+}
+
 // put get config default : keys.json
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -81,6 +155,11 @@ async fn main() -> Result<()> {
 
     info!("Start client with config: {:?}", config);
 
+    let icda_config = match config.icda_config.as_ref() {
+        Some(path) => IcdaConfig::from_file(path)?,
+        None => IcdaConfig::default(),
+    };
+
     if let Some(mode) = config.mode {
         match mode {
             Mode::Canister => {
@@ -89,6 +168,7 @@ async fn main() -> Result<()> {
                     config.identity.path,
                     config.batch.batch_number,
                     config.blob_key.path,
+                    icda_config,
                     cli.commands,
                 )
                 .await;
@@ -103,6 +183,7 @@ async fn main() -> Result<()> {
             config.identity.path,
             config.batch.batch_number,
             config.blob_key.path,
+            icda_config,
             cli.commands,
         )
         .await;
@@ -115,9 +196,10 @@ async fn talk_to_canister(
     identity_path: String,
     batch_number: usize,
     key_path: String,
+    icda_config: IcdaConfig,
     commands: Commands,
 ) {
-    let da = ICDA::new(identity_path).await.unwrap();
+    let da = ICDA::new(identity_path.clone(), icda_config).await.unwrap();
 
     match commands {
         Commands::Put => {
@@ -132,6 +214,95 @@ async fn talk_to_canister(
         Commands::Init(InitConfigPath { path }) => {
             let _ = init_canister(path, &da).await;
         }
+        Commands::TopUp(TopUpArgs { canister, e8s }) => {
+            let agent = new_agent(&identity_path).unwrap();
+            match top_up_canister(
+                &CmcAgent::new(agent.clone()),
+                &LedgerAgent::new(agent),
+                Principal::from_str(&canister).unwrap(),
+                Tokens::from_e8s(e8s),
+            )
+            .await
+            {
+                Ok(cycles) => info!("top up canister: {}, cycles: {}", canister, cycles),
+                Err(e) => error!("top up canister: {} failed: {:?}", canister, e),
+            }
+        }
+        Commands::Monitor(MonitorArgs {
+            min_tcycles,
+            top_up_e8s,
+            max_e8s_per_day,
+        }) => {
+            let agent = new_agent(&identity_path).unwrap();
+            let min_cycles = min_tcycles as u128 * 1_000_000_000_000;
+            let top_up = CmcTopUp::new(agent, min_cycles, top_up_e8s, max_e8s_per_day);
+
+            let mut monitor = CycleMonitor::new(da).with_top_up(Arc::new(top_up));
+            monitor.storage_threshold = min_cycles;
+            monitor.signature_threshold = min_cycles;
+            monitor.start_monitor().await;
+        }
+        Commands::Provision(ProvisionConfig {
+            wasm,
+            subnets,
+            replicas,
+            controllers,
+            output,
+        }) => {
+            let agent = new_agent(&identity_path).unwrap();
+            let subnets = if subnets.is_empty() {
+                SUBNETS.iter().map(|s| s.to_string()).collect()
+            } else {
+                subnets
+            };
+            let args = ProvisionArgs {
+                subnets: subnets
+                    .iter()
+                    .map(|s| Principal::from_str(s).unwrap())
+                    .collect(),
+                replicas,
+                wasm_path: wasm,
+                controllers: controllers
+                    .iter()
+                    .map(|c| Principal::from_str(c).unwrap())
+                    .collect(),
+                output,
+            };
+            match provision_canisters(agent, args, &da).await {
+                Ok(config) => info!("provisioned collections: {:?}", config),
+                Err(e) => error!("provision failed: {:?}", e),
+            }
+        }
+        Commands::Upgrade(UpgradeConfig {
+            wasm,
+            kind,
+            collections,
+        }) => {
+            let agent = new_agent(&identity_path).unwrap();
+            let collections = match kind {
+                CanisterKind::Signature => da
+                    .config
+                    .signature_canisters
+                    .iter()
+                    .map(|cid| vec![*cid])
+                    .collect::<Vec<_>>(),
+                CanisterKind::Storage => {
+                    let config = match collections {
+                        Some(path) => IcdaConfig::from_file(path).unwrap(),
+                        None => da.config.as_ref().clone(),
+                    };
+                    config
+                        .collections
+                        .iter()
+                        .map(|c| c.iter().map(|info| info.canister_id).collect())
+                        .collect()
+                }
+            };
+            match upgrade_canisters(agent, kind, wasm, collections).await {
+                Ok(_) => info!("upgrade finished"),
+                Err(e) => error!("upgrade halted: {:?}", e),
+            }
+        }
     }
 }
 
@@ -9,12 +9,9 @@ use tokio::fs::{File, ReadDir};
 use tokio::io::AsyncReadExt;
 use tokio::select;
 
+use crate::error::{IcdaError, Result};
 use crate::icda::ICDA;
 
-pub async fn cycle_monitor() {
-    unimplemented!()
-}
-
 pub const BACKUP_PATH: &str = "backup";
 
 pub struct ReUploader {
@@ -23,21 +20,17 @@ pub struct ReUploader {
 }
 
 impl ReUploader {
-    pub async fn new(icda: ICDA) -> Self {
+    pub async fn new(icda: ICDA) -> Result<Self> {
         // check if backup file exist
-        if !std::path::Path::new(BACKUP_PATH).exists() {
-            tokio::fs::create_dir(BACKUP_PATH)
-                .await
-                .expect("failed to create backup dir");
+        if !icda.backup_dir.exists() {
+            tokio::fs::create_dir_all(icda.backup_dir.as_path()).await?;
         }
 
-        let backup = tokio::fs::read_dir(BACKUP_PATH)
-            .await
-            .expect("failed to read backup dir");
+        let backup = tokio::fs::read_dir(icda.backup_dir.as_path()).await?;
 
         let icda = Arc::new(icda);
 
-        Self { backup, icda }
+        Ok(Self { backup, icda })
     }
 
     pub async fn start_uploader(self) {
@@ -62,8 +55,16 @@ impl ReUploader {
                     Some(entry) => {
                         tracing::warn!("ICDA ReUploader: monitor: catch file {:?}", entry.path());
                         let path = entry.path();
-                        let fut = ReUploader::reupload(self.icda.clone(), path);
-                        tokio::spawn(fut);
+                        let icda = self.icda.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = ReUploader::reupload(icda, path.clone()).await {
+                                tracing::error!(
+                                    "ICDA ReUploader: reupload {:?} failed: {:?}",
+                                    path,
+                                    e
+                                );
+                            }
+                        });
                     }
                     None => {
                         tracing::info!("ICDA ReUploader: monitor: no files to reupload");
@@ -80,26 +81,36 @@ impl ReUploader {
         }
     }
 
-    async fn reupload(icda: Arc<ICDA>, path: PathBuf) {
-        let mut buffer = Vec::new();
+    // files of other canisters are left in the backup dir
+    async fn reupload(icda: Arc<ICDA>, path: PathBuf) -> Result<()> {
+        // (canister_id)_chunk_(chunk_index)_(system_time).bin
+        let canister_id = match Self::parse_canister_id_from_file_name(&path) {
+            Some(canister_id) => canister_id,
+            None => {
+                tracing::warn!("ICDA ReUploader: skip unknown backup file {:?}", path);
+                return Ok(());
+            }
+        };
+        let sc = match icda.storage_canisters_map.get(&canister_id) {
+            Some(sc) => sc.clone(),
+            None => {
+                tracing::warn!(
+                    "ICDA ReUploader: skip {:?}, canister {} is not in the config",
+                    path,
+                    canister_id.to_text()
+                );
+                return Ok(());
+            }
+        };
 
+        let mut buffer = Vec::new();
         {
-            let mut file = File::open(&path).await.expect("failed to open file");
-            file.read_to_end(&mut buffer)
-                .await
-                .expect("failed to read file");
+            let mut file = File::open(&path).await?;
+            file.read_to_end(&mut buffer).await?;
         }
 
-        // file's name is : chunk-{canister_id}-{chunk.index}.bin
-        let canister_id = Self::parse_canister_id_from_file_name(&path);
-        let sc = icda
-            .storage_canisters_map
-            .get(&canister_id)
-            .expect("failed to get canister")
-            .clone();
-
         let serialized_chunk: Vec<u8> =
-            bincode::deserialize(&buffer).expect("failed to deserialize");
+            bincode::deserialize(&buffer).map_err(|e| IcdaError::Other(e.into()))?;
         drop(buffer);
 
         loop {
@@ -111,10 +122,8 @@ impl ReUploader {
                         canister_id.to_text(),
                     );
                     // remove file
-                    tokio::fs::remove_file(path)
-                        .await
-                        .expect("failed to remove file");
-                    break;
+                    tokio::fs::remove_file(path).await?;
+                    return Ok(());
                 }
                 Err(e) => {
                     tracing::error!(
@@ -130,7 +139,7 @@ impl ReUploader {
 }
 
 impl ReUploader {
-    pub async fn save<'a, T>(data: &'a T, file_name: String)
+    pub async fn save<'a, T>(backup_dir: &Path, data: &'a T, file_name: String)
     where
         T: Serialize + Deserialize<'a>,
     {
@@ -138,15 +147,18 @@ impl ReUploader {
         let serialized = bincode::serialize(&data).unwrap();
 
         // 放到icda 的 reuploader中
-        let _ = tokio::fs::write(format!("{BACKUP_PATH}/{}", file_name,), serialized).await;
+        let _ = tokio::fs::write(backup_dir.join(file_name), serialized).await;
     }
 
-    // (canister_id)_chunk_(system_time).bin
-    pub fn generate_backup_file_name(canister_id: String, data_type: &str) -> String {
+    // (canister_id)_chunk_(chunk_index)_(system_time).bin
+    // chunks uploaded at the same time can fail in the same nanosecond, the index keeps them apart
+    pub fn generate_backup_file_name(canister_id: String, data_type: &str, index: usize) -> String {
         canister_id
             + "_"
             + data_type
             + "_"
+            + &index.to_string()
+            + "_"
             + &SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Failed to get timestamp")
@@ -155,17 +167,14 @@ impl ReUploader {
             + ".bin"
     }
 
-    // (canister_id)_chunk_(system_time).bin
-    fn parse_canister_id_from_file_name(path: &Path) -> Principal {
-        let re = Regex::new(r"([a-z0-9-]+)_chunk_\d+\.bin").expect("failed to compile regex");
-        let file_name = path
-            .file_name()
-            .expect("failed to get file name")
-            .to_str()
-            .expect("failed to convert to str");
-        let captures = re.captures(file_name).expect("failed to match regex");
-        let canister_id = captures.get(1).expect("failed to get canister id").as_str();
-        Principal::from_text(canister_id).expect("failed to parse canister id")
+    // (canister_id)_chunk_(chunk_index)_(system_time).bin, older files have no chunk index
+    // None if the file was not written by `save`
+    fn parse_canister_id_from_file_name(path: &Path) -> Option<Principal> {
+        let re =
+            Regex::new(r"^([a-z0-9-]+)_chunk_(\d+_)?\d+\.bin$").expect("failed to compile regex");
+        let file_name = path.file_name()?.to_str()?;
+        let captures = re.captures(file_name)?;
+        Principal::from_text(captures.get(1)?.as_str()).ok()
     }
 }
 
@@ -177,8 +186,8 @@ mod test {
     fn test_generate_backup_file_name() {
         let canister_id = "r2xtu-uiaaa-aaaag-alf6q-cai".to_string();
         let data_type = "chunk";
-        let file_name = ReUploader::generate_backup_file_name(canister_id, data_type);
-        let re = Regex::new(r"([a-z0-9-]+)_chunk_\d+\.bin").expect("failed to compile regex");
+        let file_name = ReUploader::generate_backup_file_name(canister_id, data_type, 3);
+        let re = Regex::new(r"([a-z0-9-]+)_chunk_3_\d+\.bin").expect("failed to compile regex");
         assert!(re.is_match(&file_name));
     }
 
@@ -186,9 +195,15 @@ mod test {
     fn test_parse_canister_id_from_file_name() {
         let canister_id = "r2xtu-uiaaa-aaaag-alf6q-cai".to_string();
         let data_type = "chunk";
-        let file_name = ReUploader::generate_backup_file_name(canister_id.clone(), data_type);
+        let file_name = ReUploader::generate_backup_file_name(canister_id.clone(), data_type, 3);
         let path = PathBuf::from(file_name);
-        let parsed_canister_id = ReUploader::parse_canister_id_from_file_name(&path);
+        let parsed_canister_id = ReUploader::parse_canister_id_from_file_name(&path).unwrap();
+        assert_eq!(canister_id, parsed_canister_id.to_text());
+        assert!(ReUploader::parse_canister_id_from_file_name(Path::new("notes.txt")).is_none());
+
+        // written before the chunk index was added
+        let path = PathBuf::from(format!("{}_chunk_1700000000000000000.bin", canister_id));
+        let parsed_canister_id = ReUploader::parse_canister_id_from_file_name(&path).unwrap();
         assert_eq!(canister_id, parsed_canister_id.to_text());
     }
 }
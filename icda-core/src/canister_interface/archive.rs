@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use candid::{Decode, Encode, Principal};
+
+use crate::canister_interface::rr_agent::RoundRobinAgent;
+use crate::canister_interface::signature::{BatchConfirmation, ConfirmationStatus};
+use crate::error::Result;
+
+/// Holds the confirmations pruned from the signature canister.
+#[derive(Clone)]
+pub struct ArchiveCanister {
+    pub canister_id: Principal,
+    pub agent: Arc<RoundRobinAgent>,
+}
+
+impl ArchiveCanister {
+    pub fn new(canister_id: Principal, agent: Arc<RoundRobinAgent>) -> Self {
+        Self { canister_id, agent }
+    }
+
+    pub async fn get_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+        let arg = Encode!(&digest)?;
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_confirmation", arg)
+            .await?;
+        let confirmation = Decode!(&res, ConfirmationStatus)?;
+        Ok(confirmation)
+    }
+
+    pub async fn get_batch(&self, batch_index: u32) -> Result<Option<BatchConfirmation>> {
+        let arg = Encode!(&batch_index)?;
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_batch", arg)
+            .await?;
+        let batch = Decode!(&res, Option<BatchConfirmation>)?;
+        Ok(batch)
+    }
+}
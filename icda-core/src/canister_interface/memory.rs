@@ -0,0 +1,496 @@
+//! In-memory storage and signature canisters for testing ICDA without a replica.
+//! They follow the chunking, paging and batching of the deployed canisters, but
+//! skip caller checks, cycles, expiry and certification.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use candid::{Decode, Principal};
+use rs_merkle::algorithms::Sha256;
+use rs_merkle::MerkleTree;
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use sha2::Digest;
+
+use crate::canister_interface::signature::{
+    BatchConfirmation, ChainHead, Confirmation, ConfirmationLeaf, ConfirmationStatus, CyclesStatus,
+    DigestReports, Proof, SignatureCanisterConfig, SignatureClient,
+};
+use crate::canister_interface::storage::{
+    Blob, BlobChunk, StorageCanisterConfig, StorageClient, StorageStats, CHUNK_SIZE,
+};
+use crate::error::{IcdaError, Result};
+
+// reported by cycles() and cycles_status()
+const MEMORY_CYCLES: u128 = 10_000_000_000_000;
+
+struct MemoryBlob {
+    data: Vec<u8>,
+    timestamp: u128,
+    // chunks still missing, chunks may arrive in any order
+    received: Vec<bool>,
+}
+
+impl MemoryBlob {
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+}
+
+/// Storage canister keeping blobs in a map. Complete blobs are reported to the
+/// linked signature canisters, like `insert_digest` calls of the real canister.
+pub struct MemoryStorageCanister {
+    canister_id: Principal,
+    config: Mutex<StorageCanisterConfig>,
+    blobs: Mutex<HashMap<[u8; 32], MemoryBlob>>,
+    signature_canisters: Vec<Arc<MemorySignatureCanister>>,
+    available: AtomicBool,
+}
+
+impl MemoryStorageCanister {
+    pub fn new(
+        canister_id: Principal,
+        signature_canisters: Vec<Arc<MemorySignatureCanister>>,
+    ) -> Self {
+        Self {
+            canister_id,
+            config: Mutex::new(StorageCanisterConfig::default()),
+            blobs: Mutex::new(HashMap::new()),
+            signature_canisters,
+            available: AtomicBool::new(true),
+        }
+    }
+
+    /// An unavailable canister fails every call, like a stopped canister.
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::SeqCst);
+    }
+
+    pub fn contains(&self, digest: &[u8; 32]) -> bool {
+        self.blobs
+            .lock()
+            .unwrap()
+            .get(digest)
+            .is_some_and(MemoryBlob::is_complete)
+    }
+
+    fn check_available(&self) -> Result<()> {
+        if !self.available.load(Ordering::SeqCst) {
+            return Err(IcdaError::Rejected {
+                canister: self.canister_id,
+                message: "canister is unavailable".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn notify_signature_canisters(&self, digest: [u8; 32], size: usize, timestamp: u128) {
+        for sc in self.signature_canisters.iter() {
+            sc.insert_digest(digest, size, self.canister_id, timestamp);
+        }
+    }
+
+    // same paging as get_blob_with_index of the storage canister
+    fn page(&self, digest: &[u8; 32], index: usize) -> Blob {
+        let query_response_size = self.config.lock().unwrap().query_response_size;
+        let mut blob = Blob::default();
+
+        if let Some(stored) = self.blobs.lock().unwrap().get(digest) {
+            let data = &stored.data;
+            let start = (query_response_size * index).min(data.len());
+            if data.len() > query_response_size * (index + 1) {
+                blob.data
+                    .extend_from_slice(&data[start..query_response_size * (index + 1)]);
+                blob.next = Some(index as u64 + 1);
+            } else {
+                blob.data.extend_from_slice(&data[start..]);
+            }
+        }
+        blob
+    }
+}
+
+#[async_trait]
+impl StorageClient for MemoryStorageCanister {
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    async fn get_blob(&self, digest: [u8; 32]) -> Result<Blob> {
+        self.check_available()?;
+        Ok(self.page(&digest, 0))
+    }
+
+    async fn get_blob_with_index(&self, digest: [u8; 32], index: u64) -> Result<Blob> {
+        self.check_available()?;
+        Ok(self.page(&digest, index as usize))
+    }
+
+    async fn contains_blob(&self, digest: [u8; 32]) -> Result<bool> {
+        self.check_available()?;
+        Ok(self.contains(&digest))
+    }
+
+    async fn save_blob(&self, serialized_chunk: Vec<u8>) -> Result<()> {
+        self.check_available()?;
+        let chunk = Decode!(&serialized_chunk, BlobChunk)?;
+
+        let complete = {
+            let mut blobs = self.blobs.lock().unwrap();
+            let blob = blobs.entry(chunk.digest).or_insert_with(|| MemoryBlob {
+                data: vec![0; chunk.total],
+                timestamp: chunk.timestamp,
+                received: vec![false; chunk.total.div_ceil(CHUNK_SIZE).max(1)],
+            });
+            // the blob is already stored, a retried chunk is ignored
+            if blob.is_complete() {
+                return Ok(());
+            }
+
+            let start = chunk.index * CHUNK_SIZE;
+            let end = (start + CHUNK_SIZE).min(chunk.total);
+            if blob.data.len() != chunk.total
+                || chunk.index >= blob.received.len()
+                || start > end
+                || end - start != chunk.data.len()
+            {
+                return Err(IcdaError::Rejected {
+                    canister: self.canister_id,
+                    message: format!(
+                        "storage canister: chunk out of range: chunk index: {}, {}",
+                        chunk.index,
+                        hex::encode(chunk.digest)
+                    ),
+                });
+            }
+            blob.data[start..end].copy_from_slice(&chunk.data);
+            blob.received[chunk.index] = true;
+
+            let complete = blob.is_complete();
+            if complete {
+                let digest: [u8; 32] = sha2::Sha256::digest(&blob.data).into();
+                if digest != chunk.digest {
+                    blobs.remove(&chunk.digest);
+                    return Err(IcdaError::Rejected {
+                        canister: self.canister_id,
+                        message: format!(
+                            "storage canister: digest not match: chunk index: {}, {}",
+                            chunk.index,
+                            hex::encode(chunk.digest)
+                        ),
+                    });
+                }
+            }
+            complete
+        };
+
+        if complete {
+            self.notify_signature_canisters(chunk.digest, chunk.total, chunk.timestamp);
+        }
+        Ok(())
+    }
+
+    async fn notify_generate_confirmation(&self, digest: [u8; 32]) -> Result<()> {
+        self.check_available()?;
+        let blob = self
+            .blobs
+            .lock()
+            .unwrap()
+            .get(&digest)
+            .map(|blob| (blob.data.len(), blob.timestamp));
+        if let Some((size, timestamp)) = blob {
+            self.notify_signature_canisters(digest, size, timestamp);
+        }
+        Ok(())
+    }
+
+    async fn cycles(&self) -> Result<u128> {
+        self.check_available()?;
+        Ok(MEMORY_CYCLES)
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        self.check_available()?;
+        let threshold = self.config.lock().unwrap().canister_storage_threshold;
+        let blobs = self.blobs.lock().unwrap();
+        Ok(StorageStats {
+            blob_count: blobs.len() as u64,
+            stable_memory_bytes: blobs.values().map(|blob| blob.data.len() as u64).sum(),
+            threshold,
+        })
+    }
+
+    /// Only `query_response_size` is used, signature canisters are linked in `new`.
+    async fn update_config(&self, config: &StorageCanisterConfig) -> Result<()> {
+        self.check_available()?;
+        *self.config.lock().unwrap() = config.clone();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct SignatureState {
+    digest_reports: HashMap<[u8; 32], DigestReports>,
+    index_map: HashMap<[u8; 32], u32>,
+    batches: BTreeMap<u32, BatchConfirmation>,
+    // the latest sealed batch, prev_hash of the next one
+    chain_head: Option<ChainHead>,
+}
+
+/// Signature canister batching digests once the replica quorum is met. A full
+/// batch is sealed and signed right away with a local secp256k1 key.
+pub struct MemorySignatureCanister {
+    canister_id: Principal,
+    secret_key: SecretKey,
+    config: Mutex<SignatureCanisterConfig>,
+    state: Mutex<SignatureState>,
+    available: AtomicBool,
+}
+
+impl MemorySignatureCanister {
+    /// The signing key is derived from the canister id, so it is stable across runs.
+    pub fn new(canister_id: Principal) -> Self {
+        let seed: [u8; 32] = sha2::Sha256::digest(canister_id.as_slice()).into();
+        let secret_key = SecretKey::from_slice(&seed).expect("invalid secret key");
+
+        Self {
+            canister_id,
+            secret_key,
+            config: Mutex::new(SignatureCanisterConfig::default()),
+            state: Mutex::new(SignatureState::default()),
+            available: AtomicBool::new(true),
+        }
+    }
+
+    /// An unavailable canister fails every call, like a stopped canister.
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::SeqCst);
+    }
+
+    pub fn get_batch(&self, batch_index: u32) -> Option<BatchConfirmation> {
+        self.state
+            .lock()
+            .unwrap()
+            .batches
+            .get(&batch_index)
+            .cloned()
+    }
+
+    pub fn get_chain_head(&self) -> Option<ChainHead> {
+        self.state.lock().unwrap().chain_head.clone()
+    }
+
+    /// What a storage canister sends after saving a blob.
+    pub fn insert_digest(
+        &self,
+        digest: [u8; 32],
+        size: usize,
+        canister: Principal,
+        timestamp: u128,
+    ) {
+        let config = self.config.lock().unwrap().clone();
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        // mismatched size or timestamp is ignored, like record_digest_report
+        let reports = state
+            .digest_reports
+            .entry(digest)
+            .or_insert_with(|| DigestReports {
+                size,
+                timestamp,
+                canisters: Vec::new(),
+                first_report: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .expect("Failed to get timestamp")
+                    .as_nanos() as u64,
+            });
+        if reports.size != size || reports.timestamp != timestamp {
+            return;
+        }
+        if !reports.canisters.contains(&canister) {
+            reports.canisters.push(canister);
+        }
+        if reports.canisters.len() < config.replica_quorum()
+            || state.index_map.contains_key(&digest)
+        {
+            return;
+        }
+        let reports = reports.clone();
+
+        // batch indexes start at 1, the last batch is the current one
+        let current_index = match state.batches.last_key_value() {
+            Some((index, batch)) if batch.timestamp == 0 => *index,
+            Some((index, _)) => index + 1,
+            None => 1,
+        };
+        state.index_map.insert(digest, current_index);
+        let batch = state
+            .batches
+            .entry(current_index)
+            .or_insert_with(|| BatchConfirmation {
+                signature: None,
+                root: [0; 32],
+                nodes: Vec::new(),
+                index: current_index,
+                prev_hash: [0; 32],
+                timestamp: 0,
+                signing_cycles: 0,
+                legacy: false,
+            });
+        batch.nodes.push(ConfirmationLeaf {
+            digest,
+            size: reports.size,
+            timestamp: reports.timestamp,
+            canisters: reports.canisters,
+        });
+        if batch.nodes.len() < config.confirmation_batch_size {
+            return;
+        }
+
+        let mut batch = batch.clone();
+        let leaf_hashes = batch
+            .nodes
+            .iter()
+            .map(|leaf| leaf.hash())
+            .collect::<Vec<_>>();
+        batch.root = MerkleTree::<Sha256>::from_leaves(&leaf_hashes)
+            .root()
+            .unwrap_or_default();
+        batch.prev_hash = state
+            .chain_head
+            .as_ref()
+            .map(|head| head.header_hash)
+            .unwrap_or_default();
+        batch.timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Failed to get timestamp")
+            .as_nanos() as u64;
+
+        let msg = Message::from_digest(batch.header_hash());
+        let signature = Secp256k1::new().sign_ecdsa(&msg, &self.secret_key);
+        batch.signature = Some(hex::encode(signature.serialize_compact()));
+
+        state.chain_head = Some(ChainHead {
+            batch_index: current_index,
+            header_hash: batch.header_hash(),
+        });
+        state.batches.insert(current_index, batch);
+    }
+
+    fn check_available(&self) -> Result<()> {
+        if !self.available.load(Ordering::SeqCst) {
+            return Err(IcdaError::Rejected {
+                canister: self.canister_id,
+                message: "canister is unavailable".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SignatureClient for MemorySignatureCanister {
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    async fn public_key(&self) -> Result<Vec<u8>> {
+        self.check_available()?;
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &self.secret_key);
+        Ok(public_key.serialize().to_vec())
+    }
+
+    async fn init(&self) -> Result<()> {
+        self.check_available()
+    }
+
+    async fn get_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+        self.check_available()?;
+        let state = self.state.lock().unwrap();
+
+        let batch = match state.index_map.get(&digest) {
+            None if state.digest_reports.contains_key(&digest) => {
+                return Ok(ConfirmationStatus::Pending)
+            }
+            None => return Ok(ConfirmationStatus::Invalid),
+            Some(batch_index) => state.batches.get(batch_index).ok_or_else(|| {
+                IcdaError::NotFound(format!("memory signature canister: batch {}", batch_index))
+            })?,
+        };
+        let signature = match batch.signature.clone() {
+            Some(signature) => signature,
+            None => return Ok(ConfirmationStatus::Pending),
+        };
+        let leaf_index = match batch.nodes.iter().position(|leaf| leaf.digest == digest) {
+            Some(leaf_index) => leaf_index,
+            None => return Ok(ConfirmationStatus::Invalid),
+        };
+
+        let leaf_hashes = batch
+            .nodes
+            .iter()
+            .map(|leaf| leaf.hash())
+            .collect::<Vec<_>>();
+        let proof_bytes = MerkleTree::<Sha256>::from_leaves(&leaf_hashes)
+            .proof(&[leaf_index])
+            .to_bytes();
+        let leaf = &batch.nodes[leaf_index];
+
+        Ok(ConfirmationStatus::Confirmed(Confirmation {
+            root: batch.root,
+            proof: Proof {
+                proof_bytes,
+                leaf_index,
+                leaf_count: batch.nodes.len(),
+                leaf_digest: digest,
+                blob_size: leaf.size,
+                timestamp: leaf.timestamp,
+                storage_canisters: leaf.canisters.clone(),
+            },
+            signature,
+            batch_index: batch.index,
+            prev_hash: batch.prev_hash,
+            legacy: false,
+        }))
+    }
+
+    /// Nothing is certified in memory, same as `get_confirmation`.
+    async fn get_certified_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+        self.get_confirmation(digest).await
+    }
+
+    async fn cycles_status(&self) -> Result<CyclesStatus> {
+        self.check_available()?;
+        let reserve = self.config.lock().unwrap().cycles_reserve();
+        let state = self.state.lock().unwrap();
+        let signed_batches = state
+            .batches
+            .values()
+            .filter(|batch| batch.signature.is_some())
+            .count() as u64;
+
+        Ok(CyclesStatus {
+            balance: MEMORY_CYCLES,
+            reserve,
+            sign_cost: 0,
+            paused: false,
+            total_signing_cycles: 0,
+            signed_batches,
+            failed_signs: 0,
+            unsigned_batches: 0,
+        })
+    }
+
+    async fn get_config(&self) -> Result<SignatureCanisterConfig> {
+        self.check_available()?;
+        Ok(self.config.lock().unwrap().clone())
+    }
+
+    async fn update_config(&self, config: &SignatureCanisterConfig) -> Result<()> {
+        self.check_available()?;
+        *self.config.lock().unwrap() = config.clone();
+        Ok(())
+    }
+}
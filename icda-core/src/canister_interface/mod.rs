@@ -1,3 +1,5 @@
+pub mod archive;
+pub mod memory;
 pub mod rr_agent;
 pub mod signature;
 pub mod storage;
@@ -2,11 +2,12 @@ use candid::Principal;
 use ic_agent::agent::http_transport::reqwest_transport::reqwest::Client;
 use ic_agent::agent::http_transport::route_provider::RoundRobinRouteProvider;
 use ic_agent::agent::http_transport::ReqwestTransport;
-use ic_agent::identity::BasicIdentity;
-use ic_agent::Agent;
+use ic_agent::{lookup_value, Agent, Certificate, Identity};
 use std::sync::Arc;
 
-const BOUNDARY_NODE_POOL: [&str; 15] = [
+use crate::error::{IcdaError, Result};
+
+pub const BOUNDARY_NODE_POOL: [&str; 15] = [
     "63.251.162.12",
     "147.75.202.74",
     "162.247.129.233",
@@ -30,31 +31,42 @@ pub struct RoundRobinAgent {
 }
 
 impl RoundRobinAgent {
-    pub fn new(identity: BasicIdentity) -> Self {
+    /// Fails with [`IcdaError::InvalidConfig`] on a malformed boundary node.
+    pub fn new(identity: Arc<dyn Identity>, boundary_nodes: &[String]) -> Result<Self> {
+        let invalid = |e: String| {
+            IcdaError::InvalidConfig(format!("boundary nodes {:?}: {}", boundary_nodes, e))
+        };
+
         let client = Client::builder()
             .use_rustls_tls()
             .danger_accept_invalid_certs(true)
             .build()
-            .expect("Could not create HTTP client.");
+            .map_err(|e| invalid(format!("could not create HTTP client: {}", e)))?;
 
         let rr_router = Arc::new(
             RoundRobinRouteProvider::new(
-                BOUNDARY_NODE_POOL
+                boundary_nodes
                     .iter()
                     .map(|s| format!("{}{}", "https://", s))
                     .collect(),
             )
-            .unwrap(),
+            .map_err(|e| invalid(e.to_string()))?,
         );
 
-        let transport = ReqwestTransport::create_with_client_route(rr_router, client).unwrap();
+        let transport = ReqwestTransport::create_with_client_route(rr_router, client)
+            .map_err(|e| invalid(e.to_string()))?;
 
         let agent = Agent::builder()
-            .with_identity(identity)
+            .with_arc_identity(identity)
             .with_transport(transport)
             .build()
-            .unwrap();
+            .map_err(|e| invalid(e.to_string()))?;
+
+        Ok(Self { agent })
+    }
 
+    /// Wraps an agent built by the caller, e.g. one pointing at a local replica.
+    pub fn from_agent(agent: Agent) -> Self {
         Self { agent }
     }
 
@@ -67,14 +79,13 @@ impl RoundRobinAgent {
         canister_id: &Principal,
         function_name: &str,
         args: Vec<u8>,
-    ) -> anyhow::Result<Vec<u8>> {
-        let raw = self
-            .agent
+    ) -> Result<Vec<u8>> {
+        self.agent
             .update(canister_id, function_name)
             .with_arg(args)
             .call_and_wait()
-            .await?;
-        Ok(raw)
+            .await
+            .map_err(|e| IcdaError::from_agent(*canister_id, function_name, e))
     }
 
     pub async fn query_call(
@@ -82,14 +93,33 @@ impl RoundRobinAgent {
         canister_id: &Principal,
         function_name: &str,
         args: Vec<u8>,
-    ) -> anyhow::Result<Vec<u8>> {
-        let res = self
-            .agent
+    ) -> Result<Vec<u8>> {
+        self.agent
             .query(canister_id, function_name)
             .with_arg(args)
             .call()
-            .await?;
+            .await
+            .map_err(|e| IcdaError::from_agent(*canister_id, function_name, e))
+    }
+
+    /// Verifies a cbor encoded certificate against the IC root key and returns
+    /// the certified data of the canister.
+    pub fn certified_data(&self, canister_id: &Principal, certificate: &[u8]) -> Result<Vec<u8>> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            IcdaError::InvalidConfirmation(format!("certificate: {}", e))
+        };
+        let certificate: Certificate =
+            serde_cbor::from_slice(certificate).map_err(|e| invalid(&e))?;
+        self.agent
+            .verify(&certificate, *canister_id)
+            .map_err(|e| invalid(&e))?;
 
-        Ok(res)
+        let path = [
+            "canister".as_bytes(),
+            canister_id.as_slice(),
+            "certified_data".as_bytes(),
+        ];
+        let certified_data = lookup_value(&certificate, path).map_err(|e| invalid(&e))?;
+        Ok(certified_data.to_vec())
     }
 }
@@ -1,30 +1,177 @@
+use crate::canister_interface::archive::ArchiveCanister;
 use crate::canister_interface::rr_agent::RoundRobinAgent;
+use crate::error::{IcdaError, Result};
 use crate::icda::{
     CANISTER_COLLECTIONS, COLLECTION_SIZE, CONFIRMATION_BATCH_SIZE, CONFIRMATION_LIVE_TIME,
-    DEFAULT_OWNER,
+    CYCLES_RESERVE, DEFAULT_OWNER, REPLICA_NUM,
 };
-use anyhow::{anyhow, Result};
+use crate::verify::{verify, VerifyError};
+use async_trait::async_trait;
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_agent::hash_tree::{HashTree, Label, LookupResult};
 use rs_merkle::algorithms::Sha256;
-use rs_merkle::MerkleProof;
+use rs_merkle::Hasher;
 use secp256k1::ecdsa::Signature;
 use secp256k1::{Message, PublicKey, Secp256k1};
 use serde::Serialize;
 use std::collections::HashSet;
 use std::sync::Arc;
+use tokio::sync::OnceCell;
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct Proof {
     pub proof_bytes: Vec<u8>,
     pub leaf_index: usize,
-    pub leaf_digest: [u8; 32],
+    pub leaf_count: usize,                 // leaves of the batch merkle tree
+    pub leaf_digest: [u8; 32],             // blob digest
+    pub blob_size: usize,                  // blob size in bytes
+    pub timestamp: u128,                   // blob timestamp in nanos
+    pub storage_canisters: Vec<Principal>, // canisters holding the blob
+}
+
+impl Proof {
+    /// The merkle leaf of the blob.
+    pub fn leaf_hash(&self) -> [u8; 32] {
+        leaf_hash(
+            &self.leaf_digest,
+            self.blob_size,
+            self.timestamp,
+            &self.storage_canisters,
+        )
+    }
+}
+
+/// A merkle leaf of a batch: one blob confirmed by the signature canister.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConfirmationLeaf {
+    pub digest: [u8; 32],
+    pub size: usize,
+    pub timestamp: u128,
+    pub canisters: Vec<Principal>,
+}
+
+impl ConfirmationLeaf {
+    pub fn hash(&self) -> [u8; 32] {
+        leaf_hash(&self.digest, self.size, self.timestamp, &self.canisters)
+    }
+}
+
+/// sha256(digest || size(u64 be) || timestamp(u128 be) || (len(u8) || principal)*)
+pub fn leaf_hash(
+    digest: &[u8; 32],
+    size: usize,
+    timestamp: u128,
+    canisters: &[Principal],
+) -> [u8; 32] {
+    let mut bytes = Vec::with_capacity(32 + 8 + 16 + canisters.len() * 30);
+    bytes.extend_from_slice(digest);
+    bytes.extend_from_slice(&(size as u64).to_be_bytes());
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    for canister in canisters {
+        let canister = canister.as_slice();
+        bytes.push(canister.len() as u8);
+        bytes.extend_from_slice(canister);
+    }
+    Sha256::hash(&bytes)
+}
+
+/// A whole batch of the signature canister.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BatchConfirmation {
+    pub signature: Option<String>, // None if the batch is not signed yet
+    pub root: [u8; 32],
+    pub nodes: Vec<ConfirmationLeaf>,
+    pub index: u32,
+    pub prev_hash: [u8; 32],  // header hash of the previous batch
+    pub timestamp: u64,       // sealed time in nanos, 0 if not sealed yet
+    pub signing_cycles: u128, // cycles spent on signing, 0 if not signed yet
+    pub legacy: bool,         // signed before the leaf format changed, see `Confirmation::legacy`
+}
+
+impl BatchConfirmation {
+    pub fn header_hash(&self) -> [u8; 32] {
+        header_hash(self.index, &self.root, &self.prev_hash)
+    }
+
+    /// The message the signature is on: the root for legacy batches, the header hash otherwise.
+    pub fn signed_hash(&self) -> [u8; 32] {
+        if self.legacy {
+            self.root
+        } else {
+            self.header_hash()
+        }
+    }
+}
+
+/// Cycles balance of the signature canister and what signing has cost so far.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CyclesStatus {
+    pub balance: u128,
+    pub reserve: u128,
+    pub sign_cost: u128, // cycles attached to every sign_with_ecdsa call
+    pub paused: bool,    // sealing is paused while balance < reserve + sign_cost
+    pub total_signing_cycles: u128,
+    pub signed_batches: u64,
+    pub failed_signs: u64,
+    pub unsigned_batches: u64,
+}
+
+/// The latest sealed batch of the signature canister.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ChainHead {
+    pub batch_index: u32,
+    pub header_hash: [u8; 32],
+}
+
+/// One rs_merkle multi-proof for several blobs of the same batch.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BatchProof {
+    pub batch_index: u32,
+    pub root: [u8; 32],
+    pub signature: String,
+    pub proof_bytes: Vec<u8>,
+    pub leaf_indices: Vec<usize>,
+    pub leaves: Vec<ConfirmationLeaf>,
+    pub leaf_count: usize,
+    pub prev_hash: [u8; 32],
+    pub legacy: bool,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct Confirmation {
-    pub root: [u8; 32],    // merkle root hash
-    pub proof: Proof,      // merkle proof
-    pub signature: String, // hex encoded signature
+    pub root: [u8; 32],      // merkle root hash
+    pub proof: Proof,        // merkle proof
+    pub signature: String,   // hex encoded signature on the batch header hash
+    pub batch_index: u32,    // batch index
+    pub prev_hash: [u8; 32], // header hash of the previous batch
+    /// The batch was signed before the signature canister was upgraded to the current
+    /// leaf format: the merkle leaves are the bare digests and the root is signed directly.
+    pub legacy: bool,
+}
+
+impl Confirmation {
+    /// sha256(index(u32 be) || root || prev_hash)
+    pub fn header_hash(&self) -> [u8; 32] {
+        header_hash(self.batch_index, &self.root, &self.prev_hash)
+    }
+
+    /// The message the signature is on: the root for legacy batches, the header hash otherwise.
+    pub fn signed_hash(&self) -> [u8; 32] {
+        if self.legacy {
+            self.root
+        } else {
+            self.header_hash()
+        }
+    }
+}
+
+/// sha256(index(u32 be) || root || prev_hash)
+pub fn header_hash(index: u32, root: &[u8; 32], prev_hash: &[u8; 32]) -> [u8; 32] {
+    let mut bytes = Vec::with_capacity(4 + 32 + 32);
+    bytes.extend_from_slice(&index.to_be_bytes());
+    bytes.extend_from_slice(root);
+    bytes.extend_from_slice(prev_hash);
+    Sha256::hash(&bytes)
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -34,12 +181,49 @@ pub enum ConfirmationStatus {
     Invalid,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+/// Storage canisters which reported a digest to the signature canister.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DigestReports {
+    pub size: usize,
+    pub timestamp: u128,
+    pub canisters: Vec<Principal>,
+    pub first_report: u64, // nanos
+}
+
+/// Confirmation status with the IC certificate and the witness of the batch root.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CertifiedConfirmation {
+    pub status: ConfirmationStatus,
+    pub batch_index: Option<u32>,
+    pub certificate: Vec<u8>, // cbor encoded
+    pub witness: Vec<u8>,     // cbor encoded
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct SignatureCanisterConfig {
     pub confirmation_batch_size: usize,
-    pub confirmation_live_time: u32,
+    pub confirmation_live_time: u32, // secs since the batch is sealed
     pub da_canisters: HashSet<Principal>,
-    pub owner: Principal, // who can change confirmation config
+    pub replica_quorum: Option<usize>, // how many da canisters must report a digest before confirming it
+    pub subscribers: Option<HashSet<Principal>>, // canisters notified by on_batch_confirmed
+    pub archive_canister: Option<Principal>, // expired batches are archived here before removal
+    pub cycles_reserve: Option<u128>, // batches are not sealed while the balance is below reserve + sign cost
+    pub owner: Principal,             // who can change confirmation config
+}
+
+/// Fields added after the first release are optional, None means the canister default.
+impl SignatureCanisterConfig {
+    pub fn replica_quorum(&self) -> usize {
+        self.replica_quorum.unwrap_or(REPLICA_NUM)
+    }
+
+    pub fn subscribers(&self) -> HashSet<Principal> {
+        self.subscribers.clone().unwrap_or_default()
+    }
+
+    pub fn cycles_reserve(&self) -> u128 {
+        self.cycles_reserve.unwrap_or(CYCLES_RESERVE)
+    }
 }
 
 impl Default for SignatureCanisterConfig {
@@ -52,14 +236,19 @@ impl Default for SignatureCanisterConfig {
         });
 
         Self {
-            confirmation_live_time: CONFIRMATION_LIVE_TIME, // 7 days in batch number
+            confirmation_live_time: CONFIRMATION_LIVE_TIME, // 7 days in secs
             confirmation_batch_size: CONFIRMATION_BATCH_SIZE, // 12 blobs per confirmation
             da_canisters,
+            replica_quorum: Some(REPLICA_NUM),
+            subscribers: Some(HashSet::new()),
+            archive_canister: None,
+            cycles_reserve: Some(CYCLES_RESERVE),
             owner: Principal::from_text(DEFAULT_OWNER).unwrap(),
         }
     }
 }
 
+#[derive(Debug)]
 pub enum VerifyResult {
     InvalidSignature(String),
     InvalidProof,
@@ -70,84 +259,327 @@ pub enum VerifyResult {
 pub struct SignatureCanister {
     pub canister_id: Principal,
     pub agent: Arc<RoundRobinAgent>,
+    public_key: Arc<OnceCell<Vec<u8>>>, // fetched once, or pinned
 }
 
 impl SignatureCanister {
     pub fn new(canister_id: Principal, agent: Arc<RoundRobinAgent>) -> Self {
-        Self { canister_id, agent }
+        Self {
+            canister_id,
+            agent,
+            public_key: Arc::new(OnceCell::new()),
+        }
     }
 
-    pub async fn update_config(&self, config: &SignatureCanisterConfig) -> Result<()> {
-        let arg = Encode!(config).unwrap();
-        let _ = self
-            .agent
-            .update_call(&self.canister_id, "update_config", arg)
-            .await?;
-        Ok(())
+    /// Pins the public key, confirmations are then verified without asking the canister.
+    pub fn with_public_key(mut self, public_key: Vec<u8>) -> Self {
+        self.public_key = Arc::new(OnceCell::from(public_key));
+        self
     }
 
-    pub async fn public_key(&self) -> Result<Vec<u8>> {
+    async fn fetch_public_key(&self) -> Result<Vec<u8>> {
         let raw = self
             .agent
-            .query_call(&self.canister_id, "get_public_key", Encode!().unwrap())
+            .query_call(&self.canister_id, "get_public_key", Encode!()?)
             .await?;
         let res = Decode!(&raw, Vec<u8>)?;
 
         if res.is_empty() {
-            return Err(anyhow!("public key is not init"));
+            return Err(IcdaError::NotFound(format!(
+                "public key of signature canister {}",
+                self.canister_id
+            )));
         }
 
         Ok(res)
     }
 
-    pub async fn get_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+    /// The archive canister holding the pruned confirmations, if configured.
+    pub async fn archive_canister(&self) -> Result<Option<ArchiveCanister>> {
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_archive_canister", Encode!()?)
+            .await?;
+        let archive_canister = Decode!(&res, Option<Principal>)?;
+        Ok(archive_canister.map(|cid| ArchiveCanister::new(cid, self.agent.clone())))
+    }
+
+    // pruned confirmations are only kept by the archive canister
+    async fn get_archived_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+        match self.archive_canister().await? {
+            Some(archive) => archive.get_confirmation(digest).await,
+            None => Ok(ConfirmationStatus::Invalid),
+        }
+    }
+
+    pub async fn get_batch(&self, batch_index: u32) -> Result<Option<BatchConfirmation>> {
+        let arg = Encode!(&batch_index)?;
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_batch", arg)
+            .await?;
+        let batch = Decode!(&res, Option<BatchConfirmation>)?;
+        Ok(batch)
+    }
+
+    pub async fn get_batch_by_digest(&self, digest: [u8; 32]) -> Result<Option<BatchConfirmation>> {
         let arg = Encode!(&digest)?;
         let res = self
             .agent
-            .update_call(&self.canister_id, "get_confirmation", arg)
+            .query_call(&self.canister_id, "get_batch_by_digest", arg)
             .await?;
-        let confirmation = Decode!(&res, ConfirmationStatus)?;
-        Ok(confirmation)
+        let batch = Decode!(&res, Option<BatchConfirmation>)?;
+        Ok(batch)
     }
 
-    pub async fn verify_confirmation(&self, confirmation: &Confirmation) -> VerifyResult {
-        let public_key = self.public_key().await.expect("failed to get public key");
+    /// One multi-proof per batch, unknown or unsigned digests are left out.
+    pub async fn get_confirmations(&self, digests: Vec<[u8; 32]>) -> Result<Vec<BatchProof>> {
+        let arg = Encode!(&digests)?;
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_confirmations", arg)
+            .await?;
+        let proofs = Decode!(&res, Vec<BatchProof>)?;
+        Ok(proofs)
+    }
+
+    pub async fn get_chain_head(&self) -> Result<Option<ChainHead>> {
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_chain_head", Encode!()?)
+            .await?;
+        let head = Decode!(&res, Option<ChainHead>)?;
+        Ok(head)
+    }
 
-        // verify signature
-        let compact_sig =
-            hex::decode(confirmation.signature.clone()).expect("failed to decode signature");
-        let sig = Signature::from_compact(&compact_sig).expect("failed to parse signature");
-        let msg = Message::from_digest_slice(confirmation.root.as_ref())
-            .expect("failed to parse message");
-        let pubkey = PublicKey::from_slice(&public_key).expect("failed to parse public key");
+    /// Checks that the batches in `start..=end` are all present, signed, and
+    /// each one links to the header hash of the previous one.
+    pub async fn verify_chain(&self, start: u32, end: u32) -> Result<()> {
+        let invalid = IcdaError::InvalidConfirmation;
+        let public_key = self.public_key().await?;
         let secp = Secp256k1::new();
+        let pubkey = PublicKey::from_slice(&public_key)
+            .map_err(|e| invalid(format!("verify chain: invalid public key: {}", e)))?;
+
+        let mut prev_header_hash = None;
+        for batch_index in start..=end {
+            let batch = self.get_batch(batch_index).await?.ok_or_else(|| {
+                IcdaError::NotFound(format!("verify chain: batch {}", batch_index))
+            })?;
+
+            if batch.index != batch_index {
+                return Err(invalid(format!(
+                    "verify chain: batch {} has index {}",
+                    batch_index, batch.index
+                )));
+            }
 
-        match secp.verify_ecdsa(&msg, &sig, &pubkey) {
-            Ok(_) => {
-                // verify merkle proof
-                let merkle_proof =
-                    MerkleProof::<Sha256>::try_from(confirmation.proof.proof_bytes.as_slice())
-                        .expect("failed to parse merkle proof");
-
-                if merkle_proof.verify(
-                    confirmation.root,
-                    &[confirmation.proof.leaf_index],
-                    &[confirmation.proof.leaf_digest],
-                    CONFIRMATION_BATCH_SIZE,
-                ) {
-                    VerifyResult::Valid
-                } else {
-                    VerifyResult::InvalidProof
+            // only the first batch starts the chain, legacy batches predate it
+            if batch_index > 1 && !batch.legacy && batch.prev_hash == [0; 32] {
+                return Err(invalid(format!(
+                    "verify chain: batch {} does not link to any batch",
+                    batch_index
+                )));
+            }
+            if let (Some(prev_header_hash), false) = (prev_header_hash, batch.legacy) {
+                if batch.prev_hash != prev_header_hash {
+                    return Err(invalid(format!(
+                        "verify chain: batch {} does not link to batch {}",
+                        batch_index,
+                        batch_index - 1
+                    )));
                 }
             }
-            Err(e) => VerifyResult::InvalidSignature(e.to_string()),
+
+            let signature = batch.signature.as_ref().ok_or_else(|| {
+                invalid(format!("verify chain: batch {} is not signed", batch_index))
+            })?;
+            let sig = hex::decode(signature)
+                .ok()
+                .and_then(|sig| Signature::from_compact(&sig).ok())
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "verify chain: batch {} has a malformed signature",
+                        batch_index
+                    ))
+                })?;
+            let msg = Message::from_digest(batch.signed_hash());
+            secp.verify_ecdsa(&msg, &sig, &pubkey).map_err(|e| {
+                invalid(format!(
+                    "verify chain: batch {} has invalid signature: {}",
+                    batch_index, e
+                ))
+            })?;
+
+            prev_header_hash = Some(batch.header_hash());
         }
+
+        Ok(())
+    }
+
+    pub async fn get_digest_reports(&self, digest: [u8; 32]) -> Result<Option<DigestReports>> {
+        let arg = Encode!(&digest)?;
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_digest_reports", arg)
+            .await?;
+        let reports = Decode!(&res, Option<DigestReports>)?;
+        Ok(reports)
+    }
+}
+
+/// Signature canister operations used by ICDA, implemented by [`SignatureCanister`]
+/// and by the in-memory canister of `canister_interface::memory`.
+#[async_trait]
+pub trait SignatureClient: Send + Sync {
+    fn canister_id(&self) -> Principal;
+
+    async fn public_key(&self) -> Result<Vec<u8>>;
+
+    async fn init(&self) -> Result<()>;
+
+    async fn get_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus>;
+
+    /// Like `get_confirmation`, with the batch root checked against certified data.
+    async fn get_certified_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus>;
+
+    async fn cycles_status(&self) -> Result<CyclesStatus>;
+
+    async fn get_config(&self) -> Result<SignatureCanisterConfig>;
+
+    async fn update_config(&self, config: &SignatureCanisterConfig) -> Result<()>;
+
+    async fn verify_confirmation(&self, confirmation: &Confirmation) -> Result<VerifyResult> {
+        let public_key = self.public_key().await?;
+        Ok(verify_confirmation_with_key(&public_key, confirmation))
+    }
+}
+
+/// Checks the signature on the batch header hash and the merkle proof of the blob,
+/// see [`verify`] which also checks the digest.
+pub fn verify_confirmation_with_key(
+    public_key: &[u8],
+    confirmation: &Confirmation,
+) -> VerifyResult {
+    match verify(confirmation, public_key, &confirmation.proof.leaf_digest) {
+        Ok(()) => VerifyResult::Valid,
+        Err(
+            e @ (VerifyError::MalformedPublicKey(_)
+            | VerifyError::MalformedSignature(_)
+            | VerifyError::InvalidSignature(_)),
+        ) => VerifyResult::InvalidSignature(e.to_string()),
+        Err(_) => VerifyResult::InvalidProof,
+    }
+}
+
+#[async_trait]
+impl SignatureClient for SignatureCanister {
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    async fn public_key(&self) -> Result<Vec<u8>> {
+        self.public_key
+            .get_or_try_init(|| self.fetch_public_key())
+            .await
+            .cloned()
     }
 
-    pub async fn init(&self) -> Result<()> {
+    async fn init(&self) -> Result<()> {
         let _ = self
             .agent
-            .update_call(&self.canister_id, "init", Encode!().unwrap())
+            .update_call(&self.canister_id, "init", Encode!()?)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+        let arg = Encode!(&digest)?;
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_confirmation", arg)
+            .await?;
+        let confirmation = Decode!(&res, ConfirmationStatus)?;
+
+        match confirmation {
+            ConfirmationStatus::Invalid => self.get_archived_confirmation(digest).await,
+            _ => Ok(confirmation),
+        }
+    }
+
+    /// Gets the confirmation by query and checks the batch root against the
+    /// certified data of the signature canister.
+    async fn get_certified_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+        let arg = Encode!(&digest)?;
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_certified_confirmation", arg)
+            .await?;
+        let certified = Decode!(&res, CertifiedConfirmation)?;
+
+        let (confirmation, batch_index) = match (&certified.status, certified.batch_index) {
+            (ConfirmationStatus::Confirmed(confirmation), Some(batch_index)) => {
+                (confirmation, batch_index)
+            }
+            (ConfirmationStatus::Confirmed(_), None) => {
+                return Err(IcdaError::InvalidConfirmation(
+                    "certified confirmation: missing batch index".to_string(),
+                ))
+            }
+            (ConfirmationStatus::Invalid, _) => {
+                return self.get_archived_confirmation(digest).await
+            }
+            _ => return Ok(certified.status),
+        };
+
+        let certified_data = self
+            .agent
+            .certified_data(&self.canister_id, &certified.certificate)?;
+
+        let witness: HashTree = serde_cbor::from_slice(&certified.witness).map_err(|e| {
+            IcdaError::InvalidConfirmation(format!("certified confirmation: witness: {}", e))
+        })?;
+        if witness.digest().as_slice() != certified_data.as_slice() {
+            return Err(IcdaError::InvalidConfirmation(
+                "certified confirmation: witness does not match certified data".to_string(),
+            ));
+        }
+
+        let path: [Label<Vec<u8>>; 1] = [batch_index.to_be_bytes().to_vec().into()];
+        match witness.lookup_path(&path) {
+            LookupResult::Found(root) if root == confirmation.root.as_slice() => {
+                Ok(certified.status)
+            }
+            _ => Err(IcdaError::InvalidConfirmation(format!(
+                "certified confirmation: batch root {} is not certified",
+                batch_index
+            ))),
+        }
+    }
+
+    async fn cycles_status(&self) -> Result<CyclesStatus> {
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "cycles_status", Encode!()?)
+            .await?;
+        let status = Decode!(&res, CyclesStatus)?;
+        Ok(status)
+    }
+
+    async fn get_config(&self) -> Result<SignatureCanisterConfig> {
+        let res = self
+            .agent
+            .query_call(&self.canister_id, "get_config", Encode!()?)
+            .await?;
+        let config = Decode!(&res, SignatureCanisterConfig)?;
+        Ok(config)
+    }
+
+    async fn update_config(&self, config: &SignatureCanisterConfig) -> Result<()> {
+        let arg = Encode!(config)?;
+        let _ = self
+            .agent
+            .update_call(&self.canister_id, "update_config", arg)
             .await?;
         Ok(())
     }
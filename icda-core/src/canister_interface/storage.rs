@@ -3,14 +3,15 @@ use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
 
 use crate::canister_interface::rr_agent::RoundRobinAgent;
+use crate::error::{IcdaError, Result};
 use crate::icda::{
-    CANISTER_THRESHOLD, DEFAULT_OWNER, QUERY_RESPONSE_SIZE, SIGNATURE_CANISTER, TEST_IDENTITY,
+    CANISTER_THRESHOLD, DEFAULT_OWNER, QUERY_RESPONSE_SIZE, SIGNATURE_CANISTERS, TEST_IDENTITY,
 };
-use anyhow::bail;
+use async_trait::async_trait;
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 use serde::Serialize;
 
-const CHUNK_SIZE: usize = 1 << 20; // 1 MB
+pub(crate) const CHUNK_SIZE: usize = 1 << 20; // 1 MB
 
 #[derive(Deserialize, Serialize, CandidType, Debug, Clone)]
 pub struct BlobChunk {
@@ -42,21 +43,33 @@ impl BlobChunk {
         let data_slice = Self::split_blob_into_chunks(blob);
         let mut serialized_chunks = Vec::with_capacity(data_slice.len());
         for (index, slice) in data_slice.into_iter().enumerate() {
-            let chunk = BlobChunk {
-                index,
-                digest,
-                timestamp,
-                total,
-                data: slice,
-            };
-            let serialized_chunk = Encode!(&chunk).unwrap();
-            drop(chunk); // drop the chunk
-            serialized_chunks.push(serialized_chunk);
+            serialized_chunks.push(Self::serialize(index, slice, digest, timestamp, total));
         }
         serialized_chunks
     }
 
+    pub fn serialize(
+        index: usize,
+        data: Vec<u8>,
+        digest: [u8; 32],
+        timestamp: u128,
+        total: usize,
+    ) -> Vec<u8> {
+        let chunk = BlobChunk {
+            index,
+            digest,
+            timestamp,
+            total,
+            data,
+        };
+        Encode!(&chunk).unwrap()
+    }
+
+    // an empty blob is still saved as one empty chunk
     fn split_blob_into_chunks(blob: Vec<u8>) -> Vec<Vec<u8>> {
+        if blob.is_empty() {
+            return vec![blob];
+        }
         let mut chunks = Vec::new();
         let mut start = 0;
 
@@ -75,6 +88,19 @@ impl BlobChunk {
 pub struct RoutingInfo {
     pub total_size: usize,
     pub host_canisters: Vec<Principal>,
+    /// Set if the blob is erasure coded, then `host_canisters[i]` holds shard i.
+    #[serde(default)]
+    pub erasure: Option<ErasureInfo>,
+}
+
+/// Shards of an erasure coded blob, data shards first.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErasureInfo {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub shard_size: usize,
+    /// Sha256 digest of every shard, the key of the shard in its canister.
+    pub shard_digests: Vec<[u8; 32]>,
 }
 
 impl Debug for RoutingInfo {
@@ -89,6 +115,7 @@ impl Debug for RoutingInfo {
                     .map(|p| p.to_text())
                     .collect::<Vec<_>>(),
             )
+            .field("erasure", &self.erasure)
             .finish()
     }
 }
@@ -99,10 +126,18 @@ pub struct Blob {
     pub next: Option<u64>, // next start index
 }
 
+/// Load of a storage canister, from its `stats` query.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct StorageStats {
+    pub blob_count: u64,
+    pub stable_memory_bytes: u64,
+    pub threshold: u32, // blobs kept before the oldest ones are removed
+}
+
 #[derive(Deserialize, Serialize, CandidType, Clone)]
 pub struct StorageCanisterConfig {
     pub owner: HashSet<Principal>, // who can upload to da canister
-    pub signature_canister: Principal,
+    pub signature_canisters: Vec<Principal>,
     pub query_response_size: usize,
     pub canister_storage_threshold: u32,
 }
@@ -114,7 +149,10 @@ impl Default for StorageCanisterConfig {
                 Principal::from_text(DEFAULT_OWNER).unwrap(),
                 Principal::from_text(TEST_IDENTITY).unwrap(),
             ]),
-            signature_canister: Principal::from_text(SIGNATURE_CANISTER).unwrap(),
+            signature_canisters: SIGNATURE_CANISTERS
+                .iter()
+                .map(|cid| Principal::from_text(cid).unwrap())
+                .collect(),
             query_response_size: QUERY_RESPONSE_SIZE,
             canister_storage_threshold: CANISTER_THRESHOLD,
         }
@@ -131,8 +169,41 @@ impl StorageCanister {
     pub fn new(canister_id: Principal, agent: Arc<RoundRobinAgent>) -> Self {
         Self { agent, canister_id }
     }
+}
+
+/// Storage canister operations used by ICDA, implemented by [`StorageCanister`]
+/// and by the in-memory canister of `canister_interface::memory`.
+#[async_trait]
+pub trait StorageClient: Send + Sync {
+    fn canister_id(&self) -> Principal;
+
+    /// The first page of the blob, `next` is the index of the following page.
+    async fn get_blob(&self, digest: [u8; 32]) -> Result<Blob>;
+
+    async fn get_blob_with_index(&self, digest: [u8; 32], index: u64) -> Result<Blob>;
+
+    /// Whether the blob is completely stored, `get_blob` returns no data for a missing blob.
+    async fn contains_blob(&self, digest: [u8; 32]) -> Result<bool>;
+
+    /// Takes a candid encoded [`BlobChunk`].
+    async fn save_blob(&self, serialized_chunk: Vec<u8>) -> Result<()>;
+
+    async fn notify_generate_confirmation(&self, digest: [u8; 32]) -> Result<()>;
+
+    async fn cycles(&self) -> Result<u128>;
+
+    async fn stats(&self) -> Result<StorageStats>;
+
+    async fn update_config(&self, config: &StorageCanisterConfig) -> Result<()>;
+}
+
+#[async_trait]
+impl StorageClient for StorageCanister {
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
 
-    pub async fn get_blob(&self, digest: [u8; 32]) -> anyhow::Result<Blob> {
+    async fn get_blob(&self, digest: [u8; 32]) -> Result<Blob> {
         let arg = Encode!(&digest)?;
         let raw_response = self
             .agent
@@ -142,7 +213,7 @@ impl StorageCanister {
         Ok(response)
     }
 
-    pub async fn get_blob_with_index(&self, digest: [u8; 32], index: u64) -> anyhow::Result<Blob> {
+    async fn get_blob_with_index(&self, digest: [u8; 32], index: u64) -> Result<Blob> {
         let arg = Encode!(&digest, &index)?;
         let raw_response = self
             .agent
@@ -152,20 +223,29 @@ impl StorageCanister {
         Ok(response)
     }
 
-    pub async fn save_blob(&self, serialized_chunk: Vec<u8>) -> anyhow::Result<()> {
+    async fn contains_blob(&self, digest: [u8; 32]) -> Result<bool> {
+        let arg = Encode!(&digest)?;
+        let raw_response = self
+            .agent
+            .query_call(&self.canister_id, "contains_blob", arg)
+            .await?;
+        let response = Decode!(&raw_response, bool)?;
+        Ok(response)
+    }
+
+    async fn save_blob(&self, serialized_chunk: Vec<u8>) -> Result<()> {
         let raw_response = self
             .agent
             .update_call(&self.canister_id, "save_blob", serialized_chunk)
             .await?;
         let response = Decode!(&raw_response, Result<(), String>)?;
-        if let Err(e) = response {
-            bail!("storage canister: save blob: failed to save blob: {}", e)
-        }
-
-        Ok(())
+        response.map_err(|message| IcdaError::Rejected {
+            canister: self.canister_id,
+            message,
+        })
     }
 
-    pub async fn notify_generate_confirmation(&self, digest: [u8; 32]) -> anyhow::Result<()> {
+    async fn notify_generate_confirmation(&self, digest: [u8; 32]) -> Result<()> {
         let arg = Encode!(&digest)?;
         let _ = self
             .agent
@@ -174,7 +254,25 @@ impl StorageCanister {
         Ok(())
     }
 
-    pub async fn update_config(&self, config: &StorageCanisterConfig) -> anyhow::Result<()> {
+    async fn cycles(&self) -> Result<u128> {
+        let raw_response = self
+            .agent
+            .query_call(&self.canister_id, "cycles", Encode!()?)
+            .await?;
+        let response = Decode!(&raw_response, u128)?;
+        Ok(response)
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        let raw_response = self
+            .agent
+            .query_call(&self.canister_id, "stats", Encode!()?)
+            .await?;
+        let response = Decode!(&raw_response, StorageStats)?;
+        Ok(response)
+    }
+
+    async fn update_config(&self, config: &StorageCanisterConfig) -> Result<()> {
         let arg = Encode!(&config)?;
         let _ = self
             .agent
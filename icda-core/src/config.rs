@@ -0,0 +1,175 @@
+use std::path::Path;
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::canister_interface::rr_agent::BOUNDARY_NODE_POOL;
+use crate::error::{IcdaError, Result};
+use crate::icda::{
+    CANISTER_COLLECTIONS, CHUNK_CONCURRENCY, QUERY_RESPONSE_SIZE, REPLICA_NUM, SIGNATURE_CANISTERS,
+    WRITE_QUORUM,
+};
+use crate::placement::Placement;
+
+/// A storage canister and the subnet it runs on.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CanisterInfo {
+    pub canister_id: Principal,
+    #[serde(default)]
+    pub subnet: Option<Principal>,
+}
+
+/// Reed-Solomon mode: a blob is split into `data_shards` + `parity_shards` shards,
+/// one per canister of a collection, and any `data_shards` of them rebuild it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ErasureConfig {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+/// Canister topology of an ICDA deployment, loaded from TOML or JSON.
+/// Missing fields fall back to the compiled-in constants.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct IcdaConfig {
+    /// Every blob is pushed to all the canisters of one collection, which
+    /// should run on distinct subnets.
+    pub collections: Vec<Vec<CanisterInfo>>,
+    pub replica_num: usize,
+    /// Replicas that must store a blob before the push returns.
+    pub write_quorum: usize,
+    /// Chunks uploaded at the same time to one storage canister.
+    pub chunk_concurrency: usize,
+    /// Queried in order for confirmations.
+    pub signature_canisters: Vec<Principal>,
+    /// Boundary node hosts, without the scheme.
+    pub boundary_nodes: Vec<String>,
+    pub query_response_size: usize,
+    /// Whole blobs are replicated if not set.
+    pub erasure: Option<ErasureConfig>,
+    /// How the canisters of a blob are chosen.
+    pub placement: Placement,
+}
+
+impl Default for IcdaConfig {
+    fn default() -> Self {
+        Self {
+            collections: CANISTER_COLLECTIONS
+                .iter()
+                .map(|collection| {
+                    collection
+                        .iter()
+                        .map(|cid| CanisterInfo {
+                            canister_id: Principal::from_text(cid).unwrap(),
+                            subnet: None,
+                        })
+                        .collect()
+                })
+                .collect(),
+            replica_num: REPLICA_NUM,
+            write_quorum: WRITE_QUORUM,
+            chunk_concurrency: CHUNK_CONCURRENCY,
+            signature_canisters: SIGNATURE_CANISTERS
+                .iter()
+                .map(|cid| Principal::from_text(cid).unwrap())
+                .collect(),
+            boundary_nodes: BOUNDARY_NODE_POOL.iter().map(|s| s.to_string()).collect(),
+            query_response_size: QUERY_RESPONSE_SIZE,
+            erasure: None,
+            placement: Placement::default(),
+        }
+    }
+}
+
+impl IcdaConfig {
+    /// `.json` files are parsed as JSON, anything else as TOML.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let config: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&content)
+                .map_err(|e| IcdaError::InvalidConfig(format!("{}: {}", path.display(), e)))?,
+            _ => toml::from_str(&content)
+                .map_err(|e| IcdaError::InvalidConfig(format!("{}: {}", path.display(), e)))?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.collections.is_empty() {
+            return Err(IcdaError::InvalidConfig(
+                "no storage canister collections".to_string(),
+            ));
+        }
+        if let Some(collection) = self
+            .collections
+            .iter()
+            .find(|collection| collection.len() != self.replica_num)
+        {
+            return Err(IcdaError::InvalidConfig(format!(
+                "collection {:?} has {} canisters, replica_num is {}",
+                collection,
+                collection.len(),
+                self.replica_num
+            )));
+        }
+        if let Some(collection) = self.collections.iter().find(|collection| {
+            let mut subnets = collection
+                .iter()
+                .filter_map(|info| info.subnet)
+                .collect::<Vec<_>>();
+            let known = subnets.len();
+            subnets.sort();
+            subnets.dedup();
+            subnets.len() != known
+        }) {
+            return Err(IcdaError::InvalidConfig(format!(
+                "collection {:?} has two canisters on the same subnet",
+                collection
+            )));
+        }
+        if self.write_quorum == 0 || self.write_quorum > self.replica_num {
+            return Err(IcdaError::InvalidConfig(format!(
+                "write_quorum {} is not in 1..={}",
+                self.write_quorum, self.replica_num
+            )));
+        }
+        if self.chunk_concurrency == 0 {
+            return Err(IcdaError::InvalidConfig(
+                "chunk_concurrency must be at least 1".to_string(),
+            ));
+        }
+        if let Some(erasure) = self.erasure {
+            if erasure.data_shards == 0
+                || erasure.data_shards + erasure.parity_shards != self.replica_num
+            {
+                return Err(IcdaError::InvalidConfig(format!(
+                    "erasure {:?} does not fit collections of {} canisters",
+                    erasure, self.replica_num
+                )));
+            }
+            if self.write_quorum < erasure.data_shards {
+                return Err(IcdaError::InvalidConfig(format!(
+                    "write_quorum {} is below data_shards {}",
+                    self.write_quorum, erasure.data_shards
+                )));
+            }
+        }
+        if self.signature_canisters.is_empty() {
+            return Err(IcdaError::InvalidConfig(
+                "no signature canister".to_string(),
+            ));
+        }
+        if self.boundary_nodes.is_empty() {
+            return Err(IcdaError::InvalidConfig("no boundary node".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn storage_canisters(&self) -> impl Iterator<Item = Principal> + '_ {
+        self.collections
+            .iter()
+            .flat_map(|collection| collection.iter().map(|info| info.canister_id))
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use candid::Principal;
+use futures::future::join_all;
+use tokio::select;
+
+use crate::icda::ICDA;
+
+// 1T cycles
+pub const STORAGE_CYCLES_THRESHOLD: u128 = 1_000_000_000_000;
+// the signature canister also pauses sealing below its own cycles_reserve
+pub const SIGNATURE_CYCLES_THRESHOLD: u128 = 1_000_000_000_000;
+const MONITOR_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Refills a canister whose balance fell below the monitor threshold.
+#[async_trait]
+pub trait CyclesTopUp: Send + Sync {
+    async fn top_up(&self, canister_id: Principal, balance: u128) -> Result<()>;
+}
+
+pub struct CycleMonitor {
+    icda: ICDA,
+    pub storage_threshold: u128,
+    pub signature_threshold: u128,
+    pub interval: Duration,
+    top_up: Option<Arc<dyn CyclesTopUp>>,
+}
+
+impl CycleMonitor {
+    pub fn new(icda: ICDA) -> Self {
+        Self {
+            icda,
+            storage_threshold: STORAGE_CYCLES_THRESHOLD,
+            signature_threshold: SIGNATURE_CYCLES_THRESHOLD,
+            interval: MONITOR_INTERVAL,
+            top_up: None,
+        }
+    }
+
+    /// Canisters below the threshold are topped up instead of only being logged.
+    pub fn with_top_up(mut self, top_up: Arc<dyn CyclesTopUp>) -> Self {
+        self.top_up = Some(top_up);
+        self
+    }
+
+    pub async fn start_monitor(self) {
+        let monitor_thread = async move {
+            self.monitor().await;
+        };
+
+        let ctrl_c = tokio::signal::ctrl_c();
+        select! {
+            _ = monitor_thread => {},
+            _ = ctrl_c => {
+                tracing::info!("ICDA CycleMonitor: ctrl-c received, shutdown");
+            }
+        }
+    }
+
+    async fn monitor(self) {
+        loop {
+            self.check_storage_canisters().await;
+            self.check_signature_canisters().await;
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    async fn check_storage_canisters(&self) {
+        let tasks = self
+            .icda
+            .storage_canisters_map
+            .values()
+            .map(|sc| async move { (sc.canister_id(), sc.cycles().await) });
+
+        for (canister_id, res) in join_all(tasks).await {
+            match res {
+                Ok(balance) if balance < self.storage_threshold => {
+                    tracing::warn!(
+                        "ICDA CycleMonitor: storage canister: {}, low cycles: {}, threshold: {}",
+                        canister_id,
+                        balance,
+                        self.storage_threshold
+                    );
+                    self.top_up(canister_id, balance).await;
+                }
+                Ok(balance) => tracing::info!(
+                    "ICDA CycleMonitor: storage canister: {}, cycles: {}",
+                    canister_id,
+                    balance
+                ),
+                Err(e) => tracing::error!(
+                    "ICDA CycleMonitor: storage canister: {}, failed to get cycles: {:?}",
+                    canister_id,
+                    e
+                ),
+            }
+        }
+    }
+
+    async fn check_signature_canisters(&self) {
+        for sc in self.icda.signature_canisters.iter() {
+            match sc.cycles_status().await {
+                Ok(status) if status.paused || status.balance < self.signature_threshold => {
+                    tracing::warn!(
+                        "ICDA CycleMonitor: signature canister: {}, low cycles: {:?}, threshold: {}",
+                        sc.canister_id(),
+                        status,
+                        self.signature_threshold
+                    );
+                    self.top_up(sc.canister_id(), status.balance).await;
+                }
+                Ok(status) => tracing::info!(
+                    "ICDA CycleMonitor: signature canister: {}, cycles: {}, signing cycles: {}",
+                    sc.canister_id(),
+                    status.balance,
+                    status.total_signing_cycles
+                ),
+                Err(e) => tracing::error!(
+                    "ICDA CycleMonitor: signature canister: {}, failed to get cycles status: {:?}",
+                    sc.canister_id(),
+                    e
+                ),
+            }
+        }
+    }
+
+    async fn top_up(&self, canister_id: Principal, balance: u128) {
+        let top_up = match self.top_up.as_ref() {
+            Some(top_up) => top_up,
+            None => return,
+        };
+
+        match top_up.top_up(canister_id, balance).await {
+            Ok(_) => tracing::info!("ICDA CycleMonitor: topped up canister: {}", canister_id),
+            Err(e) => tracing::error!(
+                "ICDA CycleMonitor: failed to top up canister: {}, error: {:?}",
+                canister_id,
+                e
+            ),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::error::{IcdaError, Result};
+
+/// Splits the blob into `data_shards` zero padded shards and appends
+/// `parity_shards` Reed-Solomon parity shards. Returns the shards and the shard size.
+pub fn encode(
+    blob: &[u8],
+    data_shards: usize,
+    parity_shards: usize,
+) -> Result<(Vec<Vec<u8>>, usize)> {
+    let rs = ReedSolomon::new(data_shards, parity_shards).map_err(|e| {
+        IcdaError::InvalidConfig(format!("erasure: invalid shard numbers: {:?}", e))
+    })?;
+
+    let shard_size = blob.len().div_ceil(data_shards).max(1);
+    let mut shards = Vec::with_capacity(data_shards + parity_shards);
+    for i in 0..data_shards + parity_shards {
+        let mut shard = vec![0; shard_size];
+        if i < data_shards {
+            let start = (i * shard_size).min(blob.len());
+            let end = (start + shard_size).min(blob.len());
+            shard[..end - start].copy_from_slice(&blob[start..end]);
+        }
+        shards.push(shard);
+    }
+
+    rs.encode(&mut shards)
+        .map_err(|e| IcdaError::InvalidConfig(format!("erasure: failed to encode: {:?}", e)))?;
+    Ok((shards, shard_size))
+}
+
+/// Rebuilds the blob from at least `data_shards` of the shards, missing ones are `None`.
+/// The shard numbers and `total_size` come from the blob key, a key that does not match
+/// the shards is an [`IcdaError::InvalidBlobKey`].
+pub fn reconstruct(
+    mut shards: Vec<Option<Vec<u8>>>,
+    data_shards: usize,
+    parity_shards: usize,
+    total_size: usize,
+) -> Result<Vec<u8>> {
+    if shards.len() != data_shards + parity_shards {
+        return Err(IcdaError::InvalidBlobKey(format!(
+            "erasure: expected {} shards, got {}",
+            data_shards + parity_shards,
+            shards.len()
+        )));
+    }
+
+    let rs = ReedSolomon::new(data_shards, parity_shards).map_err(|e| {
+        IcdaError::InvalidBlobKey(format!("erasure: invalid shard numbers: {:?}", e))
+    })?;
+    rs.reconstruct_data(&mut shards).map_err(|e| {
+        IcdaError::InvalidBlobKey(format!("erasure: failed to reconstruct: {:?}", e))
+    })?;
+
+    // check the size before allocating, it is not bounded by the key
+    let data_size = shards
+        .iter()
+        .take(data_shards)
+        .flatten()
+        .map(Vec::len)
+        .sum::<usize>();
+    if data_size < total_size {
+        return Err(IcdaError::InvalidBlobKey(format!(
+            "erasure: reconstructed {} bytes, expected {}",
+            data_size, total_size
+        )));
+    }
+
+    let mut blob = Vec::with_capacity(total_size);
+    for shard in shards.into_iter().take(data_shards).flatten() {
+        blob.extend(shard);
+    }
+    blob.truncate(total_size);
+    Ok(blob)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reconstruct_from_any_data_shards() {
+        let blob = (0..1000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (shards, shard_size) = encode(&blob, 4, 2).unwrap();
+        assert_eq!(shards.len(), 6);
+        assert_eq!(shard_size, 250);
+
+        let mut received = shards.into_iter().map(Some).collect::<Vec<_>>();
+        received[0] = None;
+        received[3] = None;
+        assert_eq!(
+            reconstruct(received.clone(), 4, 2, blob.len()).unwrap(),
+            blob
+        );
+
+        // a blob key claiming more data than the shards hold
+        assert!(matches!(
+            reconstruct(received, 4, 2, usize::MAX),
+            Err(IcdaError::InvalidBlobKey(_))
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+use candid::Principal;
+use ic_agent::AgentError;
+use thiserror::Error;
+
+pub type Result<T, E = IcdaError> = std::result::Result<T, E>;
+
+/// Errors returned by [`ICDA`](crate::icda::ICDA) and the canister clients.
+#[derive(Debug, Error)]
+pub enum IcdaError {
+    #[error("blob expired at {expiry_timestamp}, current time {current_timestamp}")]
+    Expired {
+        expiry_timestamp: u128,
+        current_timestamp: u128,
+    },
+
+    /// A canister of a blob key or of the placement that is not in the config.
+    #[error("unknown canister: {0}")]
+    UnknownCanister(Principal),
+
+    #[error("digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("agent error: {0}")]
+    AgentError(#[from] AgentError),
+
+    /// The canister trapped or returned an error.
+    #[error("canister {canister} rejected the call: {message}")]
+    Rejected {
+        canister: Principal,
+        message: String,
+    },
+
+    #[error("timeout: {0}")]
+    Timeout(String),
+
+    #[error("invalid confirmation: {0}")]
+    InvalidConfirmation(String),
+
+    #[error("invalid blob key: {0}")]
+    InvalidBlobKey(String),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("write quorum not met, digest: {digest}, acked: {acked}/{write_quorum}")]
+    QuorumNotMet {
+        digest: String,
+        acked: usize,
+        write_quorum: usize,
+    },
+
+    #[error("candid: {0}")]
+    Candid(#[from] candid::Error),
+
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl IcdaError {
+    /// Rejects and timeouts of a call to `method` get their own variants.
+    pub(crate) fn from_agent(canister: Principal, method: &str, e: AgentError) -> Self {
+        match e {
+            AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject) => {
+                Self::Rejected {
+                    canister,
+                    message: format!("{}: {}", method, reject.reject_message),
+                }
+            }
+            AgentError::TimeoutWaitingForResponse() => {
+                Self::Timeout(format!("canister {} did not answer {}", canister, method))
+            }
+            e => Self::AgentError(e),
+        }
+    }
+
+    pub(crate) fn digest_mismatch(expected: &[u8; 32], actual: &[u8; 32]) -> Self {
+        Self::DigestMismatch {
+            expected: hex::encode(expected),
+            actual: hex::encode(actual),
+        }
+    }
+}
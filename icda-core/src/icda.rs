@@ -1,39 +1,65 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::bail;
-use anyhow::Result;
+use backon::{ExponentialBuilder, Retryable};
+use bytes::Bytes;
 use candid::{Deserialize, Principal};
+use futures::channel::mpsc;
+use futures::future::join_all;
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
+use futures::SinkExt;
 use rand::random;
 use serde::Serialize;
 use sha2::Digest;
-use tokio::sync::Mutex;
+use tempfile::TempPath;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
 use tracing::{error, info, warn};
 
 use ic_agent::identity::BasicIdentity;
+use ic_agent::{Agent, Identity};
 
 use crate::backup::{ReUploader, BACKUP_PATH};
 use crate::canister_interface::rr_agent::RoundRobinAgent;
-use crate::canister_interface::signature::{ConfirmationStatus, SignatureCanister};
-use crate::canister_interface::storage::{BlobChunk, RoutingInfo, StorageCanister};
+use crate::canister_interface::signature::{
+    Confirmation, ConfirmationStatus, SignatureCanister, SignatureClient,
+};
+use crate::canister_interface::storage::{
+    Blob, BlobChunk, ErasureInfo, RoutingInfo, StorageCanister, StorageClient, CHUNK_SIZE,
+};
+use crate::config::{ErasureConfig, IcdaConfig};
+use crate::erasure;
+use crate::error::{IcdaError, Result};
+use crate::placement::{placement_policy, PlacementPolicy};
+use crate::verify::verify;
 
 pub const REPLICA_NUM: usize = 1;
+// canisters that must store a blob before push_blob_to_canisters returns
+pub const WRITE_QUORUM: usize = 1;
+// chunks in flight to a single storage canister
+pub const CHUNK_CONCURRENCY: usize = 4;
 pub const COLLECTION_SIZE: usize = 11;
 // 1 week in nanos
 pub const BLOB_LIVE_TIME: u128 = 7 * 24 * 60 * 60 * 1_000_000_000;
 pub const CONFIRMATION_BATCH_SIZE: usize = 12;
-pub const CONFIRMATION_LIVE_TIME: u32 = 60 * 60 * 24 * 7 + 1; // 1 week in nanos
+pub const CONFIRMATION_LIVE_TIME: u32 = 60 * 60 * 24 * 7; // 1 week in secs
 pub const QUERY_RESPONSE_SIZE: usize = 2621440; // 2.5 * 1024 * 1024 = 2.5 MB
 pub const CANISTER_THRESHOLD: u32 = 30240;
-pub const SIGNATURE_CANISTER: &str = "r34pn-oaaaa-aaaak-qinga-cai";
+// signature canister pauses sealing below this balance
+pub const CYCLES_RESERVE: u128 = 200_000_000_000;
+// signature canisters, queried in order for confirmations
+pub const SIGNATURE_CANISTERS: [&str; 1] = ["r34pn-oaaaa-aaaak-qinga-cai"];
 pub(crate) const DEFAULT_OWNER: &str =
     "ytoqu-ey42w-sb2ul-m7xgn-oc7xo-i4btp-kuxjc-b6pt4-dwdzu-kfqs4-nae";
 pub(crate) const TEST_IDENTITY: &str =
     "rtw64-dzklf-dqtzm-lhev7-ufjji-fnmfq-bkyyf-ljaod-ldfpb-w2zyk-7ae";
 
-// canister collections
+// default canister collections, see IcdaConfig
 pub const CANISTER_COLLECTIONS: [[&str; REPLICA_NUM]; COLLECTION_SIZE] = [
     ["hxctj-oiaaa-aaaap-qhltq-cai"], // nl6hn-ja4yw-wvmpy-3z2jx-ymc34-pisx3-3cp5z-3oj4a-qzzny-jbsv3-4qe
     ["v3y75-6iaaa-aaaak-qikaa-cai"], // opn46-zyspe-hhmyp-4zu6u-7sbrh-dok77-m7dch-im62f-vyimr-a3n2c-4ae
@@ -49,6 +75,73 @@ pub const CANISTER_COLLECTIONS: [[&str; REPLICA_NUM]; COLLECTION_SIZE] = [
 ];
 
 const RETRY_TIMES: usize = 3;
+// save_blob retry backoff of a single chunk
+const CHUNK_RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const CHUNK_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+// another replica is asked if no read finished within this delay
+const HEDGE_DELAY: Duration = Duration::from_secs(3);
+// pages fetched at the same time from one canister
+const PAGE_CONCURRENCY: usize = 8;
+// pages prefetched and queued by get_blob_stream
+const STREAM_BUFFERED_PAGES: usize = 2;
+// wait_for_confirmation polling backoff
+const CONFIRMATION_POLL_MIN_DELAY: Duration = Duration::from_secs(2);
+const CONFIRMATION_POLL_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// serialized chunks of one upload, held in memory or read one at a time from a spooled blob
+#[derive(Clone)]
+pub(crate) enum ChunkSource {
+    Memory(Arc<Vec<Vec<u8>>>),
+    Spool {
+        file: Arc<TempPath>,
+        digest: [u8; 32],
+        timestamp: u128,
+        total_size: usize,
+    },
+}
+
+impl ChunkSource {
+    fn len(&self) -> usize {
+        match self {
+            Self::Memory(chunks) => chunks.len(),
+            Self::Spool { total_size, .. } => total_size.div_ceil(CHUNK_SIZE).max(1),
+        }
+    }
+
+    async fn chunk(&self, index: usize) -> Result<Vec<u8>> {
+        match self {
+            Self::Memory(chunks) => Ok(chunks[index].clone()),
+            Self::Spool {
+                file,
+                digest,
+                timestamp,
+                total_size,
+            } => {
+                let start = index * CHUNK_SIZE;
+                let mut data = vec![0; CHUNK_SIZE.min(total_size - start)];
+                let mut file = tokio::fs::File::open(&**file).await?;
+                file.seek(SeekFrom::Start(start as u64)).await?;
+                file.read_exact(&mut data).await?;
+                Ok(BlobChunk::serialize(
+                    index,
+                    data,
+                    *digest,
+                    *timestamp,
+                    *total_size,
+                ))
+            }
+        }
+    }
+}
+
+/// Confirmation status of a [`BlobKey`], see [`ICDA::get_blob_key_confirmation`].
+#[derive(Clone, Debug)]
+pub enum BlobConfirmation {
+    Pending,
+    /// The confirmation of the blob digest, or of every confirmed shard.
+    Confirmed(Vec<Confirmation>),
+    Invalid,
+}
 
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct BlobKey {
@@ -69,358 +162,1075 @@ impl Debug for BlobKey {
 
 #[derive(Clone)]
 pub struct ICDA {
-    canister_collection_index: Arc<Mutex<usize>>,
-    pub storage_canisters_map: HashMap<Principal, StorageCanister>,
-    pub signature_canister: SignatureCanister,
+    pub placement: Arc<dyn PlacementPolicy>,
+    pub storage_canisters_map: HashMap<Principal, Arc<dyn StorageClient>>,
+    pub signature_canisters: Vec<Arc<dyn SignatureClient>>,
+    pub config: Arc<IcdaConfig>,
+    pub backup_dir: Arc<PathBuf>,
+    // bounds the chunks in flight to each storage canister, shared by all blobs
+    chunk_permits: Arc<HashMap<Principal, Arc<Semaphore>>>,
 }
 
 impl ICDA {
-    pub async fn new(pem_path: String) -> Result<Self> {
-        let identity = BasicIdentity::from_pem_file(pem_path)?;
+    /// Loads the identity from a PEM file and builds with the defaults of [`IcdaBuilder`].
+    pub async fn new(pem_path: String, config: IcdaConfig) -> Result<Self> {
+        let identity = BasicIdentity::from_pem_file(&pem_path)
+            .map_err(|e| IcdaError::InvalidConfig(format!("identity {}: {}", pem_path, e)))?;
+        Self::builder()
+            .with_identity(identity)
+            .with_config(config)
+            .build()
+            .await
+    }
 
-        let agent = Arc::new(RoundRobinAgent::new(identity));
+    pub fn builder() -> IcdaBuilder {
+        IcdaBuilder::default()
+    }
 
-        let mut storage_canisters_map = HashMap::new();
-        for storage_cid in CANISTER_COLLECTIONS.iter().flat_map(|x| x.iter()) {
-            let sc =
-                StorageCanister::new(Principal::from_text(storage_cid).unwrap(), agent.clone());
-            storage_canisters_map.insert(Principal::from_text(storage_cid).unwrap(), sc);
-        }
+    /// Uploads the blob to every canister of the next collection and returns once
+    /// `write_quorum` of them stored all chunks. The other replicas keep uploading,
+    /// a replica that fails leaves its chunks to the re-uploader.
+    pub async fn push_blob_to_canisters(&self, blob: Vec<u8>) -> Result<BlobKey> {
+        let blob_digest: [u8; 32] = sha2::Sha256::digest(&blob).into();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Failed to get timestamp")
+            .as_nanos();
+        let total_size = blob.len();
+        let storage_canisters = self.get_storage_canisters(&blob_digest).await?;
+        let routing_canisters = storage_canisters
+            .iter()
+            .map(|sc| sc.canister_id())
+            .collect::<Vec<_>>();
 
-        let signature_canister = SignatureCanister::new(
-            Principal::from_text(SIGNATURE_CANISTER).unwrap(),
-            agent.clone(),
-        );
+        // replicated: every canister gets the whole blob, erasure coded: one shard each
+        let (uploads, erasure) = match self.config.erasure {
+            None => {
+                let blob_chunks = ChunkSource::Memory(Arc::new(
+                    BlobChunk::generate_serialized_chunks(blob, blob_digest, timestamp),
+                ));
+                let uploads = storage_canisters
+                    .into_iter()
+                    .map(|sc| (sc, blob_chunks.clone()))
+                    .collect::<Vec<_>>();
+                (uploads, None)
+            }
+            Some(ErasureConfig {
+                data_shards,
+                parity_shards,
+            }) => {
+                let (shards, shard_size) = erasure::encode(&blob, data_shards, parity_shards)?;
+                drop(blob);
 
-        if let Ok(res) = signature_canister.public_key().await {
-            if !res.is_empty() {
-                info!(
-                    "ICDA::new(): signature canister public key: {:?}",
-                    hex::encode(res)
-                );
-            } else {
-                match signature_canister.init().await {
-                    Ok(_) => {
-                        info!("ICDA::new(): signature canister init success");
-                    }
-                    Err(e) => {
-                        bail!(
-                            "ICDA::new(): signature canister init failed, error: {:?}",
-                            e
-                        );
-                    }
+                let mut shard_digests = Vec::with_capacity(shards.len());
+                let mut uploads = Vec::with_capacity(shards.len());
+                for (sc, shard) in storage_canisters.into_iter().zip(shards) {
+                    let shard_digest: [u8; 32] = sha2::Sha256::digest(&shard).into();
+                    shard_digests.push(shard_digest);
+                    let shard_chunks =
+                        BlobChunk::generate_serialized_chunks(shard, shard_digest, timestamp);
+                    uploads.push((sc, ChunkSource::Memory(Arc::new(shard_chunks))));
                 }
+
+                let erasure = ErasureInfo {
+                    data_shards,
+                    parity_shards,
+                    shard_size,
+                    shard_digests,
+                };
+                (uploads, Some(erasure))
             }
-        }
+        };
 
-        let canister_collection_index = Arc::new(Mutex::new(random::<usize>() % COLLECTION_SIZE));
+        self.push_to_canisters(blob_digest, uploads).await?;
 
-        let _self = Self {
-            canister_collection_index,
-            storage_canisters_map,
-            signature_canister,
+        let blob_key = BlobKey {
+            digest: blob_digest,
+            expiry_timestamp: timestamp + BLOB_LIVE_TIME,
+            routing_info: RoutingInfo {
+                total_size,
+                host_canisters: routing_canisters,
+                erasure,
+            },
         };
 
-        // create backup thread
-        let _icda = _self.clone();
-        let reuploader = ReUploader::new(_icda).await;
-        tokio::spawn(reuploader.start_uploader());
-
-        Ok(_self)
+        Ok(blob_key)
     }
 
-    pub async fn push_blob_to_canisters(&self, blob: Vec<u8>) -> Result<BlobKey> {
-        let blob_digest: [u8; 32] = sha2::Sha256::digest(&blob).into();
+    /// Like [`ICDA::push_blob_to_canisters`] without holding the blob in memory. The blob is
+    /// hashed while it is spooled to a temporary file, the replicas then read their chunks
+    /// from it. Erasure coding needs the whole blob, in that mode it is read back into memory.
+    pub async fn push_blob_stream(&self, mut reader: impl AsyncRead + Unpin) -> Result<BlobKey> {
+        let spool = tempfile::Builder::new()
+            .prefix("icda_blob_")
+            .tempfile()?
+            .into_temp_path();
+        let mut file = tokio::fs::File::create(&spool).await?;
+        let mut hasher = sha2::Sha256::new();
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut total_size = 0;
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n]).await?;
+            total_size += n;
+        }
+        file.flush().await?;
+        drop(file);
+
+        if self.config.erasure.is_some() {
+            let blob = tokio::fs::read(&spool).await?;
+            return self.push_blob_to_canisters(blob).await;
+        }
+
+        let blob_digest: [u8; 32] = hasher.finalize().into();
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Failed to get timestamp")
             .as_nanos();
-        let total_size = blob.len();
-        let storage_canisters = self.get_storage_canisters().await;
+        let storage_canisters = self.get_storage_canisters(&blob_digest).await?;
         let routing_canisters = storage_canisters
             .iter()
-            .map(|sc| sc.canister_id)
+            .map(|sc| sc.canister_id())
             .collect::<Vec<_>>();
 
-        let blob_chunks = Arc::new(BlobChunk::generate_serialized_chunks(
-            blob,
-            blob_digest,
+        // the file is removed once the last replica finished uploading
+        let chunks = ChunkSource::Spool {
+            file: Arc::new(spool),
+            digest: blob_digest,
             timestamp,
-        ));
+            total_size,
+        };
+        let uploads = storage_canisters
+            .into_iter()
+            .map(|sc| (sc, chunks.clone()))
+            .collect();
+        self.push_to_canisters(blob_digest, uploads).await?;
 
-        #[cfg(feature = "client")]
-        {
-            let mut tasks = vec![];
+        Ok(BlobKey {
+            digest: blob_digest,
+            expiry_timestamp: timestamp + BLOB_LIVE_TIME,
+            routing_info: RoutingInfo {
+                total_size,
+                host_canisters: routing_canisters,
+                erasure: None,
+            },
+        })
+    }
 
-            for sc in storage_canisters.clone() {
-                let _chunks = blob_chunks.clone();
-                let fut = async move {
-                    let cid = sc.canister_id;
-                    let hexed_digest = hex::encode(blob_digest);
-                    match Self::push_chunks_to_canister(sc, _chunks).await {
-                        Ok(_) => {
-                            info!(
-                                "ICDA::save_blob_chunk(): cid = {}, digest: {}, success",
-                                cid.to_text(),
-                                hexed_digest
-                            );
-                        }
-                        Err(e) => {
-                            error!(
-                                "ICDA::save_blob_chunk(): cid = {}, digest: {}, error: {:?}",
-                                cid.to_text(),
-                                hexed_digest,
-                                e
-                            );
-                        }
+    // every canister keeps uploading in the background after the write quorum is met
+    async fn push_to_canisters(
+        &self,
+        blob_digest: [u8; 32],
+        uploads: Vec<(Arc<dyn StorageClient>, ChunkSource)>,
+    ) -> Result<()> {
+        let replicas = uploads.len();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(replicas.max(1));
+        for (sc, _chunks) in uploads.into_iter() {
+            let _tx = tx.clone();
+            let backup_dir = self.backup_dir.clone();
+            let placement = self.placement.clone();
+            let permits = self.chunk_permits(&sc.canister_id());
+            let fut = async move {
+                let cid = sc.canister_id();
+                let hexed_digest = hex::encode(blob_digest);
+                let res = Self::push_chunks_to_canister(sc, _chunks, permits, &backup_dir).await;
+                match res.as_ref() {
+                    Ok(_) => {
+                        info!(
+                            "ICDA::save_blob_chunk(): cid = {}, digest: {}, success",
+                            cid.to_text(),
+                            hexed_digest
+                        );
                     }
-                };
-                tasks.push(tokio::spawn(fut));
+                    Err(e) => {
+                        error!(
+                            "ICDA::save_blob_chunk(): cid = {}, digest: {}, error: {:?}",
+                            cid.to_text(),
+                            hexed_digest,
+                            e
+                        );
+                    }
+                }
+                placement.observe(cid, res.is_ok());
+                let _ = _tx.send((cid, res.is_ok())).await;
+            };
+            tokio::spawn(fut);
+        }
+        drop(tx);
+
+        let write_quorum = self.config.write_quorum;
+        let mut acked = 0;
+        let mut failed = vec![];
+        while acked < write_quorum {
+            match rx.recv().await {
+                Some((_, true)) => acked += 1,
+                Some((cid, false)) => failed.push(cid),
+                None => break,
+            }
+
+            if failed.len() > replicas.saturating_sub(write_quorum) {
+                break;
             }
-            futures::future::join_all(tasks).await;
         }
 
-        #[cfg(feature = "server")]
-        {
-            for sc in storage_canisters.into_iter() {
-                let _chunks = blob_chunks.clone();
-                let fut = async move {
-                    let cid = sc.canister_id;
-                    let hexed_digest = hex::encode(blob_digest);
-                    match Self::push_chunks_to_canister(sc, _chunks).await {
-                        Ok(_) => {
-                            info!(
-                                "ICDA::save_blob_chunk(): cid = {}, digest: {}, success",
-                                cid.to_text(),
-                                hexed_digest
+        if acked < write_quorum {
+            error!(
+                "ICDA::push_blob_to_canisters(): write quorum not met, digest: {}, acked: {}/{}, failed canisters: {:?}",
+                hex::encode(blob_digest),
+                acked,
+                write_quorum,
+                failed.iter().map(|cid| cid.to_text()).collect::<Vec<_>>()
+            );
+            return Err(IcdaError::QuorumNotMet {
+                digest: hex::encode(blob_digest),
+                acked,
+                write_quorum,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_blob_from_canisters(&self, blob_key: BlobKey) -> Result<Vec<u8>> {
+        let mut storage_canisters = self.host_canisters(&blob_key)?;
+
+        if let Some(erasure) = blob_key.routing_info.erasure.as_ref() {
+            return Self::get_erasure_coded_blob(
+                &blob_key,
+                erasure,
+                storage_canisters,
+                self.config.query_response_size,
+            )
+            .await;
+        }
+
+        // start at a random replica to spread the reads
+        if !storage_canisters.is_empty() {
+            let start = random::<usize>() % storage_canisters.len();
+            storage_canisters.rotate_left(start);
+        }
+
+        let digest = blob_key.digest;
+        let size = blob_key.routing_info.total_size;
+        let page_size = self.config.query_response_size;
+        let requests = storage_canisters
+            .into_iter()
+            .map(|sc| async move {
+                let cid = sc.canister_id();
+                Self::get_blob_from_canister(sc, digest, size, page_size)
+                    .await
+                    .inspect_err(|e| {
+                        error!("ICDA::get_blob(): cid: {}, error: {:?}", cid.to_text(), e)
+                    })
+            })
+            .collect();
+
+        let mut blobs = Self::hedged(requests, 1).await?;
+        let (_, blob) = blobs
+            .pop()
+            .ok_or_else(|| IcdaError::NotFound(format!("blob {}", hex::encode(digest))))?;
+        Ok(blob)
+    }
+
+    /// Streams the blob page by page, holding at most a few pages in memory. A replica that
+    /// fails hands over to the next one at the same page. The digest is checked once the
+    /// blob is complete, a mismatch is returned as the last item. Erasure coded blobs are
+    /// rebuilt in memory and returned as a single item.
+    pub fn get_blob_stream(
+        &self,
+        blob_key: BlobKey,
+    ) -> Result<impl Stream<Item = Result<Bytes>> + Send + Unpin> {
+        let mut storage_canisters = self.host_canisters(&blob_key)?;
+        let (mut tx, rx) = mpsc::channel(STREAM_BUFFERED_PAGES);
+
+        if blob_key.routing_info.erasure.is_some() {
+            let icda = self.clone();
+            tokio::spawn(async move {
+                let res = icda.get_blob_from_canisters(blob_key).await;
+                let _ = tx.send(res.map(Bytes::from)).await;
+            });
+            return Ok(rx);
+        }
+
+        // start at a random replica to spread the reads
+        if !storage_canisters.is_empty() {
+            let start = random::<usize>() % storage_canisters.len();
+            storage_canisters.rotate_left(start);
+        }
+
+        let digest = blob_key.digest;
+        let size = blob_key.routing_info.total_size;
+        let page_size = self.config.query_response_size.max(1);
+        let page_num = size.div_ceil(page_size).max(1);
+        // the task stops once the receiver is dropped
+        let fut = async move {
+            let mut hasher = sha2::Sha256::new();
+            let mut next_page = 0;
+            for sc in storage_canisters {
+                // a missing blob reads as one empty page, like an empty blob
+                if size == 0 {
+                    match sc.contains_blob(digest).await {
+                        Ok(true) => {}
+                        res => {
+                            warn!(
+                                "ICDA::get_blob_stream(): cid: {}, empty blob not found: {:?}",
+                                sc.canister_id().to_text(),
+                                res
                             );
+                            continue;
+                        }
+                    }
+                }
+                let mut pages = stream::iter(next_page..page_num)
+                    .map(|index| Self::get_page(sc.as_ref(), digest, index as u64))
+                    .buffered(STREAM_BUFFERED_PAGES);
+                while let Some(res) = pages.next().await {
+                    let expected = page_size.min(size - next_page * page_size);
+                    let page = match res {
+                        Ok(page) if page.data.len() == expected => page,
+                        Ok(page) => {
+                            warn!(
+                                "ICDA::get_blob_stream(): cid: {}, page: {}, got {} bytes, expected {}",
+                                sc.canister_id().to_text(),
+                                next_page,
+                                page.data.len(),
+                                expected
+                            );
+                            break;
                         }
                         Err(e) => {
-                            error!(
-                                "ICDA::save_blob_chunk(): cid = {}, digest: {}, error: {:?}",
-                                cid.to_text(),
-                                hexed_digest,
+                            warn!(
+                                "ICDA::get_blob_stream(): cid: {}, page: {}, error: {:?}",
+                                sc.canister_id().to_text(),
+                                next_page,
                                 e
                             );
+                            break;
                         }
+                    };
+
+                    hasher.update(&page.data);
+                    next_page += 1;
+                    if tx.send(Ok(Bytes::from(page.data))).await.is_err() {
+                        return;
                     }
-                };
-                tokio::spawn(fut);
-            }
-        }
+                }
 
-        drop(blob_chunks);
+                if next_page == page_num {
+                    break;
+                }
+            }
 
-        let blob_key = BlobKey {
-            digest: blob_digest,
-            expiry_timestamp: timestamp + BLOB_LIVE_TIME,
-            routing_info: RoutingInfo {
-                total_size,
-                host_canisters: routing_canisters,
-            },
+            let res = if next_page < page_num {
+                Err(IcdaError::NotFound(format!(
+                    "blob {}, got {} of {} pages, all replicas failed",
+                    hex::encode(digest),
+                    next_page,
+                    page_num
+                )))
+            } else {
+                let blob_digest: [u8; 32] = hasher.finalize().into();
+                if blob_digest == digest {
+                    return;
+                }
+                Err(IcdaError::digest_mismatch(&digest, &blob_digest))
+            };
+            let _ = tx.send(res).await;
         };
+        tokio::spawn(fut);
 
-        Ok(blob_key)
+        Ok(rx)
     }
 
-    pub async fn get_blob_from_canisters(&self, blob_key: BlobKey) -> Result<Vec<u8>> {
-        let blob_key = Arc::new(blob_key);
-
+    // storage canisters holding the blob, if it did not expire
+    fn host_canisters(&self, blob_key: &BlobKey) -> Result<Vec<Arc<dyn StorageClient>>> {
         // inspect expiry timestamp
-        let current_timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let current_timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Failed to get timestamp")
+            .as_nanos();
 
         if blob_key.expiry_timestamp < current_timestamp {
-            bail!(
-                "ICDA::get_blob(): expired: key.expiry_timestamp = {:?}, current_timestamp = {:?}",
-                blob_key.expiry_timestamp,
-                current_timestamp
-            );
+            return Err(IcdaError::Expired {
+                expiry_timestamp: blob_key.expiry_timestamp,
+                current_timestamp,
+            });
         }
 
-        let storage_canisters = blob_key
+        blob_key
             .routing_info
             .host_canisters
             .iter()
             .map(|cid| {
                 self.storage_canisters_map
                     .get(cid)
-                    .expect("Failed to get storage canister")
-                    .clone()
+                    .cloned()
+                    .ok_or(IcdaError::UnknownCanister(*cid))
             })
-            .collect::<Vec<_>>();
+            .collect()
+    }
 
-        // get from canisters
-        let (tx, mut rx) = tokio::sync::mpsc::channel(REPLICA_NUM);
-        for sc in storage_canisters {
-            let _tx = tx.clone();
-            let _key = blob_key.clone();
-            let fut = async move {
-                let cid = sc.canister_id;
-                let res = Self::get_blob_from_canister(sc, _key).await;
-                match res {
-                    Ok(blob) => {
-                        let _ = _tx.send(blob).await;
-                    }
-                    Err(e) => {
-                        error!("ICDA::get_blob(): cid: {}, error: {:?}", cid.to_text(), e);
+    /// Confirmation of the blob of a key. A replicated blob is confirmed by its digest.
+    /// The canisters of an erasure coded blob only store and report their shards, so it
+    /// is confirmed once `data_shards` shards are, which is enough to rebuild it.
+    pub async fn get_blob_key_confirmation(&self, blob_key: &BlobKey) -> Result<BlobConfirmation> {
+        let erasure = match blob_key.routing_info.erasure.as_ref() {
+            Some(erasure) => erasure,
+            None => {
+                return Ok(match self.get_blob_confirmation(blob_key.digest).await? {
+                    ConfirmationStatus::Confirmed(confirmation) => {
+                        BlobConfirmation::Confirmed(vec![confirmation])
                     }
-                }
-                drop(_tx);
-            };
-            tokio::spawn(fut);
+                    ConfirmationStatus::Pending => BlobConfirmation::Pending,
+                    ConfirmationStatus::Invalid => BlobConfirmation::Invalid,
+                })
+            }
+        };
+
+        let statuses = join_all(
+            erasure
+                .shard_digests
+                .iter()
+                .map(|shard_digest| self.get_blob_confirmation(*shard_digest)),
+        )
+        .await;
+
+        let mut confirmations = Vec::with_capacity(statuses.len());
+        let mut pending = 0;
+        let mut last_error = None;
+        for status in statuses {
+            match status {
+                Ok(ConfirmationStatus::Confirmed(confirmation)) => confirmations.push(confirmation),
+                Ok(ConfirmationStatus::Pending) => pending += 1,
+                Ok(ConfirmationStatus::Invalid) => {}
+                Err(e) => last_error = Some(e),
+            }
         }
 
-        drop(tx);
+        if confirmations.len() >= erasure.data_shards {
+            return Ok(BlobConfirmation::Confirmed(confirmations));
+        }
+        if confirmations.len() + pending >= erasure.data_shards {
+            return Ok(BlobConfirmation::Pending);
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(BlobConfirmation::Invalid),
+        }
+    }
 
-        loop {
-            tokio::select! {
-                msg = rx.recv() => {
-                    match msg {
-                        Some(blob) => {
-                            return Ok(blob);
-                        },
-                        None => {
-                            // No more senders and no message received
-                            error!("All senders are closed and no more messages.");
-                            break;
+    /// Queries the signature canisters in order and returns the first confirmation
+    /// that verifies. Falls back to `Pending` or `Invalid` if none is confirmed.
+    /// Erasure coded blobs are confirmed by shard, see [`ICDA::get_blob_key_confirmation`].
+    pub async fn get_blob_confirmation(&self, digest: [u8; 32]) -> Result<ConfirmationStatus> {
+        let mut pending = false;
+        let mut answered = false;
+        let mut last_error = None;
+
+        for sc in self.signature_canisters.iter() {
+            match sc.get_certified_confirmation(digest).await {
+                Ok(ConfirmationStatus::Confirmed(confirmation)) => {
+                    // the key is fetched once per signature canister
+                    match sc
+                        .public_key()
+                        .await
+                        .map(|public_key| verify(&confirmation, &public_key, &digest))
+                    {
+                        Ok(Ok(())) => return Ok(ConfirmationStatus::Confirmed(confirmation)),
+                        Ok(Err(res)) => {
+                            answered = true;
+                            warn!(
+                                "ICDA::get_confirmation(): signature canister: {}, digest: {}, invalid confirmation: {}",
+                                sc.canister_id().to_text(),
+                                hex::encode(digest),
+                                res
+                            );
+                        }
+                        Err(e) => {
+                            warn!(
+                                "ICDA::get_confirmation(): signature canister: {}, failed to verify confirmation, error: {}",
+                                sc.canister_id().to_text(),
+                                e
+                            );
+                            last_error = Some(e);
                         }
-                    }
-                },
-                _ = tokio::time::sleep(Duration::from_secs(1)) => {
-                    if rx.is_closed() {
-                        error!("ICDA: get_blob_from_canisters: all senders are closed");
-                        break;
                     }
                 }
+                Ok(ConfirmationStatus::Pending) => {
+                    answered = true;
+                    pending = true;
+                }
+                Ok(ConfirmationStatus::Invalid) => answered = true,
+                Err(e) => {
+                    warn!(
+                        "ICDA::get_confirmation(): signature canister: {}, failed to get confirmation, error: {}",
+                        sc.canister_id().to_text(),
+                        e
+                    );
+                    last_error = Some(e);
+                }
             }
         }
 
-        bail!("ICDA::get_blob(): failed to get blob")
+        if !answered {
+            return Err(last_error
+                .unwrap_or_else(|| IcdaError::InvalidConfig("no signature canister".to_string())));
+        }
+
+        if pending {
+            Ok(ConfirmationStatus::Pending)
+        } else {
+            Ok(ConfirmationStatus::Invalid)
+        }
     }
 
-    pub async fn get_blob_confirmation(
-        sc: &SignatureCanister,
+    /// Polls the signature canisters with exponential backoff until the blob is
+    /// confirmed or the timeout is reached.
+    pub async fn wait_for_confirmation(
+        &self,
         digest: [u8; 32],
-    ) -> Result<ConfirmationStatus> {
-        match sc.get_confirmation(digest).await {
-            Ok(confirmation) => Ok(confirmation),
-            Err(e) => {
-                bail!(
-                    "ICDA::get_confirmation(): failed to get confirmation, error: {}",
+        timeout: Duration,
+    ) -> Result<Confirmation> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut delay = CONFIRMATION_POLL_MIN_DELAY;
+
+        loop {
+            match self.get_blob_confirmation(digest).await {
+                Ok(ConfirmationStatus::Confirmed(confirmation)) => return Ok(confirmation),
+                // Invalid: the storage canister may not have reported the digest yet
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "ICDA::wait_for_confirmation(): digest: {}, error: {:?}",
+                    hex::encode(digest),
                     e
-                );
+                ),
+            }
+
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Err(IcdaError::Timeout(format!(
+                    "confirmation of digest {}",
+                    hex::encode(digest)
+                )));
+            }
+
+            tokio::time::sleep(delay.min(deadline - now)).await;
+            delay = (delay * 2).min(CONFIRMATION_POLL_MAX_DELAY);
+        }
+    }
+}
+
+/// Builds an [`ICDA`] from an identity or a prebuilt agent.
+///
+/// By default it initializes the signature canisters and spawns the
+/// [`ReUploader`], both of which can be turned off for local replicas and tests.
+pub struct IcdaBuilder {
+    identity: Option<Arc<dyn Identity>>,
+    agent: Option<Agent>,
+    config: IcdaConfig,
+    storage_clients: HashMap<Principal, Arc<dyn StorageClient>>,
+    signature_clients: HashMap<Principal, Arc<dyn SignatureClient>>,
+    placement: Option<Arc<dyn PlacementPolicy>>,
+    backup_dir: PathBuf,
+    start_background_tasks: bool,
+    init_signature_canisters: bool,
+}
+
+impl Default for IcdaBuilder {
+    fn default() -> Self {
+        Self {
+            identity: None,
+            agent: None,
+            config: IcdaConfig::default(),
+            storage_clients: HashMap::new(),
+            signature_clients: HashMap::new(),
+            placement: None,
+            backup_dir: PathBuf::from(BACKUP_PATH),
+            start_background_tasks: true,
+            init_signature_canisters: true,
+        }
+    }
+}
+
+impl IcdaBuilder {
+    /// Used with the boundary nodes of the config, ignored if an agent is given.
+    pub fn with_identity(mut self, identity: impl Identity + 'static) -> Self {
+        self.identity = Some(Arc::new(identity));
+        self
+    }
+
+    /// The agent is used as is, the caller fetches the root key for a local replica.
+    pub fn with_agent(mut self, agent: Agent) -> Self {
+        self.agent = Some(agent);
+        self
+    }
+
+    pub fn with_config(mut self, config: IcdaConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Used for its canister id instead of a canister called through the agent.
+    pub fn with_storage_client(mut self, client: Arc<dyn StorageClient>) -> Self {
+        self.storage_clients.insert(client.canister_id(), client);
+        self
+    }
+
+    /// Used for its canister id instead of a canister called through the agent.
+    pub fn with_signature_client(mut self, client: Arc<dyn SignatureClient>) -> Self {
+        self.signature_clients.insert(client.canister_id(), client);
+        self
+    }
+
+    /// Replaces the policy chosen by `placement` in the config.
+    pub fn with_placement_policy(mut self, placement: Arc<dyn PlacementPolicy>) -> Self {
+        self.placement = Some(placement);
+        self
+    }
+
+    /// Where chunks that failed to upload are saved for the re-uploader.
+    pub fn with_backup_dir(mut self, backup_dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = backup_dir.into();
+        self
+    }
+
+    pub fn start_background_tasks(mut self, start: bool) -> Self {
+        self.start_background_tasks = start;
+        self
+    }
+
+    /// Calls `init` on signature canisters without a public key yet.
+    pub fn init_signature_canisters(mut self, init: bool) -> Self {
+        self.init_signature_canisters = init;
+        self
+    }
+
+    pub async fn build(self) -> Result<ICDA> {
+        let config = self.config;
+        config.validate()?;
+
+        // only needed for canisters without an injected client
+        let agent = match (self.agent, self.identity) {
+            (Some(agent), _) => Some(RoundRobinAgent::from_agent(agent)),
+            (None, Some(identity)) => Some(RoundRobinAgent::new(identity, &config.boundary_nodes)?),
+            (None, None) => None,
+        }
+        .map(Arc::new);
+        let agent = || {
+            agent.clone().ok_or_else(|| {
+                IcdaError::InvalidConfig("neither agent nor identity is given".to_string())
+            })
+        };
+
+        let mut storage_canisters_map = HashMap::new();
+        for storage_cid in config.storage_canisters() {
+            let sc: Arc<dyn StorageClient> = match self.storage_clients.get(&storage_cid) {
+                Some(client) => client.clone(),
+                None => Arc::new(StorageCanister::new(storage_cid, agent()?)),
+            };
+            storage_canisters_map.insert(storage_cid, sc);
+        }
+
+        let mut signature_canisters = Vec::with_capacity(config.signature_canisters.len());
+        for signature_cid in config.signature_canisters.iter() {
+            let signature_canister: Arc<dyn SignatureClient> =
+                match self.signature_clients.get(signature_cid) {
+                    Some(client) => client.clone(),
+                    None => Arc::new(SignatureCanister::new(*signature_cid, agent()?)),
+                };
+
+            // every shard is stored by a single canister, a quorum above 1 never confirms it
+            if config.erasure.is_some() {
+                let replica_quorum = signature_canister.get_config().await?.replica_quorum();
+                if replica_quorum > 1 {
+                    return Err(IcdaError::InvalidConfig(format!(
+                        "erasure needs replica_quorum 1, signature canister {} has {}",
+                        signature_cid, replica_quorum
+                    )));
+                }
+            }
+
+            if !self.init_signature_canisters {
+                signature_canisters.push(signature_canister);
+                continue;
+            }
+
+            if let Ok(res) = signature_canister.public_key().await {
+                if !res.is_empty() {
+                    info!(
+                        "IcdaBuilder::build(): signature canister: {}, public key: {:?}",
+                        signature_cid,
+                        hex::encode(res)
+                    );
+                } else {
+                    match signature_canister.init().await {
+                        Ok(_) => {
+                            info!(
+                                "IcdaBuilder::build(): signature canister: {} init success",
+                                signature_cid
+                            );
+                        }
+                        Err(e) => {
+                            error!(
+                                "IcdaBuilder::build(): signature canister: {} init failed, error: {:?}",
+                                signature_cid,
+                                e
+                            );
+                            return Err(e);
+                        }
+                    }
+                }
             }
+
+            signature_canisters.push(signature_canister);
+        }
+
+        let placement = self
+            .placement
+            .unwrap_or_else(|| placement_policy(&config, &storage_canisters_map));
+
+        let chunk_permits = storage_canisters_map
+            .keys()
+            .map(|cid| (*cid, Arc::new(Semaphore::new(config.chunk_concurrency))))
+            .collect();
+
+        let _self = ICDA {
+            placement,
+            storage_canisters_map,
+            signature_canisters,
+            config: Arc::new(config),
+            backup_dir: Arc::new(self.backup_dir),
+            chunk_permits: Arc::new(chunk_permits),
+        };
+
+        if self.start_background_tasks {
+            // create backup thread
+            let _icda = _self.clone();
+            let reuploader = ReUploader::new(_icda).await?;
+            tokio::spawn(reuploader.start_uploader());
         }
+
+        Ok(_self)
     }
 }
 
 impl ICDA {
-    // push chunks to a single canister
+    fn chunk_permits(&self, canister_id: &Principal) -> Arc<Semaphore> {
+        self.chunk_permits
+            .get(canister_id)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(Semaphore::new(self.config.chunk_concurrency)))
+    }
+
+    fn chunk_backoff() -> ExponentialBuilder {
+        ExponentialBuilder::default()
+            .with_min_delay(CHUNK_RETRY_MIN_DELAY)
+            .with_max_delay(CHUNK_RETRY_MAX_DELAY)
+            .with_max_times(RETRY_TIMES)
+            .with_jitter()
+    }
+
+    // push chunks to a single canister, at most `permits` of them loaded at the same time.
+    // every chunk is retried on its own, the chunks that still fail are saved for the re-uploader
     pub(crate) async fn push_chunks_to_canister(
-        sc: StorageCanister,
-        chunks: Arc<Vec<Vec<u8>>>,
+        sc: Arc<dyn StorageClient>,
+        chunks: ChunkSource,
+        permits: Arc<Semaphore>,
+        backup_dir: &Path,
     ) -> Result<()> {
-        for chunk in chunks.iter() {
-            // simple re-upload
-            for i in 0..RETRY_TIMES {
-                if let Err(e) = sc.save_blob(chunk.to_vec()).await {
-                    warn!(
-                        "ICDA::save_blob_chunk(): cid: {}, error: {:?}, retry after 5 seconds",
-                        sc.canister_id.to_text(),
-                        e
-                    );
-                    if i == 2 {
-                        // save to local storage
-                        let file_name = ReUploader::generate_backup_file_name(
-                            sc.canister_id.to_text(),
-                            "chunk",
-                        );
-
+        let chunks = &chunks;
+        let uploads = (0..chunks.len()).map(|index| {
+            let sc = sc.clone();
+            let permits = permits.clone();
+            async move {
+                let _permit = permits
+                    .acquire()
+                    .await
+                    .map_err(|e| IcdaError::Other(e.into()))?;
+                let chunk = chunks.chunk(index).await?;
+                let res = (|| async { sc.save_blob(chunk.clone()).await })
+                    .retry(&Self::chunk_backoff())
+                    .notify(|e: &IcdaError, delay: Duration| {
                         warn!(
-                            "ICDA::save_blob_chunk(): retry 3 times failed, error: {:?}. save chunk to local storage: {}/{}",
+                            "ICDA::save_blob_chunk(): cid: {}, chunk: {}, error: {:?}, retry after {:?}",
+                            sc.canister_id().to_text(),
+                            index,
                             e,
-                            BACKUP_PATH,
-                            file_name
-                        );
+                            delay
+                        )
+                    })
+                    .await;
 
-                        ReUploader::save(chunk, file_name).await;
+                if res.is_err() {
+                    // save the chunk to local storage for the re-uploader
+                    let file_name = ReUploader::generate_backup_file_name(
+                        sc.canister_id().to_text(),
+                        "chunk",
+                        index,
+                    );
+                    ReUploader::save(backup_dir, &chunk, file_name).await;
+                }
+                res
+            }
+        });
 
-                        bail!(
-                            "ICDA::save_blob_chunk(): cid: {}, error: {:?}, retry 3 times failed",
-                            sc.canister_id.to_text(),
-                            e
-                        );
-                    }
+        let mut errors = join_all(uploads)
+            .await
+            .into_iter()
+            .filter_map(Result::err)
+            .collect::<Vec<_>>();
+        let failed = errors.len();
+        let Some(last_error) = errors.pop() else {
+            return Ok(());
+        };
 
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                } else {
+        warn!(
+            "ICDA::save_blob_chunk(): cid: {}, retry {} times failed, error: {:?}. {} of {} chunks saved to local storage: {:?}",
+            sc.canister_id().to_text(),
+            RETRY_TIMES,
+            last_error,
+            failed,
+            chunks.len(),
+            backup_dir
+        );
+        Err(last_error)
+    }
+
+    // get blob from canister and check digest. the pages are fetched in parallel,
+    // their number follows from the size and the query_response_size of the canisters
+    async fn get_blob_from_canister(
+        sc: Arc<dyn StorageClient>,
+        digest: [u8; 32],
+        size: usize,
+        page_size: usize,
+    ) -> Result<Vec<u8>> {
+        let page_size = page_size.max(1);
+        let page_num = size.div_ceil(page_size).max(1);
+        let mut requests = stream::iter(0..page_num as u64)
+            .map(|index| Self::get_page(sc.as_ref(), digest, index))
+            .buffered(PAGE_CONCURRENCY);
+
+        // the first page always exists, a later page may fail when the canister pages by
+        // another query_response_size and has fewer pages than expected. the size comes from
+        // the blob key, so stop at the last page of the canister instead of asking for all
+        let mut pages = Vec::new();
+        let mut paged_as_expected = true;
+        while let Some(res) = requests.next().await {
+            let page = match res {
+                Ok(page) => page,
+                Err(e) if pages.is_empty() => return Err(e),
+                Err(_) => {
+                    paged_as_expected = false;
                     break;
                 }
+            };
+            let index = pages.len();
+            let last = page.next.is_none();
+            paged_as_expected = if index + 1 < page_num {
+                page.data.len() == page_size && page.next == Some(index as u64 + 1)
+            } else {
+                last
+            };
+            pages.push(page);
+            if !paged_as_expected || last {
+                break;
             }
         }
+        drop(requests);
 
-        Ok(())
-    }
+        let mut blob = Vec::new();
+        if paged_as_expected && pages.len() == page_num {
+            // 按收到的数据创建buffer, 不按blob key里的size
+            blob.reserve_exact(pages.iter().map(|page| page.data.len()).sum());
+            for page in pages {
+                blob.extend(page.data);
+            }
+        } else {
+            // the canister pages by another query_response_size, follow `next` instead
+            warn!(
+                "ICDA::get_blob_from_canisters(): cid: {}, unexpected paging, fetch pages one by one",
+                sc.canister_id().to_text()
+            );
+            let mut slice = sc.get_blob(digest).await?;
+            blob.extend(slice.data);
 
-    // get blob from canister and check digest
-    async fn get_blob_from_canister(sc: StorageCanister, key: Arc<BlobKey>) -> Result<Vec<u8>> {
-        // 创建一样大小的buffer
-        let mut blob = Vec::with_capacity(key.routing_info.total_size);
+            while let Some(next_index) = slice.next {
+                // get blob by index
+                slice = sc.get_blob_with_index(digest, next_index).await?;
+                blob.extend(slice.data);
+            }
+        }
 
-        let mut slice = sc.get_blob(key.digest).await?;
-        blob.extend(slice.data);
+        // a missing blob reads as empty too, an empty blob must exist in the canister
+        if blob.is_empty() && (size != 0 || !sc.contains_blob(digest).await?) {
+            return Err(IcdaError::NotFound(format!(
+                "blob {} in canister {}",
+                hex::encode(digest),
+                sc.canister_id()
+            )));
+        }
 
-        while let Some(next_index) = slice.next {
-            // get blob by index
-            slice = sc.get_blob_with_index(key.digest, next_index).await?;
-            blob.extend(slice.data);
+        let blob_digest: [u8; 32] = sha2::Sha256::digest(&blob).into();
+        if !digest.eq(&blob_digest) {
+            return Err(IcdaError::digest_mismatch(&digest, &blob_digest));
         }
 
-        if blob.is_empty() {
-            bail!(
-                "ICDA::get_blob_from_canisters(): failed to get blob from canisters, blob is empty"
-            );
+        Ok(blob)
+    }
+
+    async fn get_page(sc: &dyn StorageClient, digest: [u8; 32], index: u64) -> Result<Blob> {
+        if index == 0 {
+            sc.get_blob(digest).await
+        } else {
+            sc.get_blob_with_index(digest, index).await
+        }
+    }
+
+    // fetch data_shards of the shards, hedged by the parity shards, and rebuild the blob
+    async fn get_erasure_coded_blob(
+        key: &BlobKey,
+        erasure: &ErasureInfo,
+        storage_canisters: Vec<Arc<dyn StorageClient>>,
+        page_size: usize,
+    ) -> Result<Vec<u8>> {
+        let shard_num = erasure.data_shards + erasure.parity_shards;
+        if storage_canisters.len() != shard_num || erasure.shard_digests.len() != shard_num {
+            return Err(IcdaError::InvalidBlobKey(format!(
+                "{} canisters and {} shard digests for {} shards",
+                storage_canisters.len(),
+                erasure.shard_digests.len(),
+                shard_num
+            )));
         }
 
+        let shard_size = erasure.shard_size;
+        let requests = storage_canisters
+            .into_iter()
+            .zip(erasure.shard_digests.iter().copied())
+            .map(|(sc, shard_digest)| async move {
+                let cid = sc.canister_id();
+                Self::get_blob_from_canister(sc, shard_digest, shard_size, page_size)
+                    .await
+                    .inspect_err(|e| {
+                        error!("ICDA::get_blob(): cid: {}, error: {:?}", cid.to_text(), e)
+                    })
+            })
+            .collect();
+
+        let mut shards = vec![None; shard_num];
+        for (index, shard) in Self::hedged(requests, erasure.data_shards).await? {
+            shards[index] = Some(shard);
+        }
+
+        let blob = erasure::reconstruct(
+            shards,
+            erasure.data_shards,
+            erasure.parity_shards,
+            key.routing_info.total_size,
+        )?;
+
         let digest: [u8; 32] = sha2::Sha256::digest(&blob).into();
         if !key.digest.eq(&digest) {
-            bail!("ICDA::get_blob_from_canisters(): blob digest not match");
+            return Err(IcdaError::digest_mismatch(&key.digest, &digest));
         }
 
         Ok(blob)
     }
 
-    // get storage canisters in the current round
-    async fn get_storage_canisters(&self) -> Vec<StorageCanister> {
-        let cids;
-        {
-            let mut index = self.canister_collection_index.lock().await;
-            cids = CANISTER_COLLECTIONS.get(*index).unwrap();
+    // runs the first `needed` requests and starts the next one whenever a request fails
+    // or none finished within HEDGE_DELAY. returns the first `needed` results with their
+    // index, the requests still running are dropped and so cancelled. if too many requests
+    // fail the last error is returned
+    async fn hedged<T, Fut>(requests: Vec<Fut>, needed: usize) -> Result<Vec<(usize, T)>>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let total = requests.len();
+        let mut pending = requests.into_iter().enumerate().rev().collect::<Vec<_>>();
+        let start = |(index, request): (usize, Fut)| async move { (index, request.await) };
+
+        let mut running = FuturesUnordered::new();
+        while running.len() < needed {
+            match pending.pop() {
+                Some(request) => running.push(start(request)),
+                None => break,
+            }
+        }
 
-            *index += 1;
-            *index %= COLLECTION_SIZE;
+        let mut done = Vec::with_capacity(needed);
+        let mut failed = 0;
+        let mut last_error = None;
+        while done.len() < needed {
+            if running.is_empty() {
+                error!(
+                    "ICDA::get_blob(): got {} of {} needed results, {} of {} requests failed",
+                    done.len(),
+                    needed,
+                    failed,
+                    total
+                );
+                return Err(last_error
+                    .unwrap_or_else(|| IcdaError::InvalidBlobKey("no host canister".to_string())));
+            }
+
+            tokio::select! {
+                Some((index, res)) = running.next() => match res {
+                    Ok(value) => done.push((index, value)),
+                    Err(e) => {
+                        failed += 1;
+                        last_error = Some(e);
+                        if let Some(request) = pending.pop() {
+                            running.push(start(request));
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(HEDGE_DELAY), if !pending.is_empty() => {
+                    if let Some(request) = pending.pop() {
+                        running.push(start(request));
+                    }
+                }
+            }
         }
 
+        Ok(done)
+    }
+
+    // storage canisters chosen by the placement policy
+    async fn get_storage_canisters(
+        &self,
+        digest: &[u8; 32],
+    ) -> Result<Vec<Arc<dyn StorageClient>>> {
+        let cids = self
+            .placement
+            .select(digest, self.config.replica_num)
+            .await?;
         let storage_canisters = cids
             .iter()
             .map(|cid| {
                 self.storage_canisters_map
-                    .get(&Principal::from_text(cid).unwrap())
-                    .expect("Failed to get storage canister")
-                    .clone()
+                    .get(cid)
+                    .cloned()
+                    .ok_or(IcdaError::UnknownCanister(*cid))
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
         info!("ICDA::get_storage_canisters(): {:?}", cids);
 
-        storage_canisters
+        Ok(storage_canisters)
     }
 }
 
 #[tokio::test]
 async fn test_icda() {
-    let icda = ICDA::new("../identity/identity.pem".to_string())
-        .await
-        .unwrap();
+    let icda = ICDA::new(
+        "../identity/identity.pem".to_string(),
+        IcdaConfig::default(),
+    )
+    .await
+    .unwrap();
 
     let blob = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 15];
 
@@ -439,3 +1249,358 @@ async fn test_icda() {
 
     assert_eq!(blob, blob2);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::canister_interface::memory::{MemorySignatureCanister, MemoryStorageCanister};
+    use crate::config::CanisterInfo;
+    use futures::FutureExt;
+    use sha2::Sha256;
+
+    struct MemoryIcda {
+        icda: ICDA,
+        storage_canisters: Vec<Arc<MemoryStorageCanister>>,
+    }
+
+    // one collection of `replica_num` in-memory storage canisters and one signature canister
+    async fn memory_icda(
+        replica_num: usize,
+        write_quorum: usize,
+        erasure: Option<ErasureConfig>,
+    ) -> MemoryIcda {
+        let signature = Arc::new(MemorySignatureCanister::new(Principal::from_slice(&[0])));
+        let storage_canisters = (1..=replica_num as u8)
+            .map(|i| {
+                Arc::new(MemoryStorageCanister::new(
+                    Principal::from_slice(&[i]),
+                    vec![signature.clone()],
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let config = IcdaConfig {
+            collections: vec![storage_canisters
+                .iter()
+                .map(|sc| CanisterInfo {
+                    canister_id: sc.canister_id(),
+                    subnet: None,
+                })
+                .collect()],
+            replica_num,
+            write_quorum,
+            signature_canisters: vec![signature.canister_id()],
+            erasure,
+            ..IcdaConfig::default()
+        };
+
+        let mut builder = ICDA::builder()
+            .with_config(config)
+            .with_signature_client(signature)
+            .with_backup_dir(std::env::temp_dir().join("icda-test-backup"))
+            .start_background_tasks(false);
+        for sc in storage_canisters.iter() {
+            builder = builder.with_storage_client(sc.clone());
+        }
+
+        MemoryIcda {
+            icda: builder.build().await.unwrap(),
+            storage_canisters,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_and_get_chunked_blob() {
+        let memory = memory_icda(1, 1, None).await;
+
+        // 4 chunks, 2 query pages
+        let blob = (0..3 * 1024 * 1024 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let blob_key = memory
+            .icda
+            .push_blob_to_canisters(blob.clone())
+            .await
+            .unwrap();
+
+        assert!(memory.storage_canisters[0].contains(&blob_key.digest));
+        let got = memory.icda.get_blob_from_canisters(blob_key).await.unwrap();
+        assert_eq!(blob, got);
+    }
+
+    #[tokio::test]
+    async fn test_push_and_get_blob_stream() {
+        let memory = memory_icda(2, 2, None).await;
+
+        let blob = (0..3 * 1024 * 1024 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let blob_key = memory.icda.push_blob_stream(blob.as_slice()).await.unwrap();
+        assert_eq!(blob_key.digest, <[u8; 32]>::from(Sha256::digest(&blob)));
+
+        let mut stream = memory.icda.get_blob_stream(blob_key).unwrap();
+        let mut got = vec![];
+        while let Some(bytes) = stream.next().await {
+            got.extend(bytes.unwrap());
+        }
+        assert_eq!(blob, got);
+    }
+
+    #[tokio::test]
+    async fn test_push_and_get_empty_blob_stream() {
+        let memory = memory_icda(2, 2, None).await;
+
+        let blob_key = memory.icda.push_blob_stream(&[][..]).await.unwrap();
+        assert_eq!(blob_key.routing_info.total_size, 0);
+        assert!(memory.storage_canisters[0].contains(&blob_key.digest));
+
+        let mut stream = memory.icda.get_blob_stream(blob_key.clone()).unwrap();
+        let mut got = vec![];
+        while let Some(bytes) = stream.next().await {
+            got.extend(bytes.unwrap());
+        }
+        assert!(got.is_empty());
+        let got = memory
+            .icda
+            .get_blob_from_canisters(blob_key.clone())
+            .await
+            .unwrap();
+        assert!(got.is_empty());
+
+        // a canister that never stored the blob reads it as empty as well
+        let other = memory_icda(1, 1, None).await;
+        let mut missing = blob_key;
+        missing.routing_info.host_canisters = vec![other.storage_canisters[0].canister_id()];
+        assert!(matches!(
+            other.icda.get_blob_from_canisters(missing.clone()).await,
+            Err(IcdaError::NotFound(_))
+        ));
+        let mut stream = other.icda.get_blob_stream(missing).unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(IcdaError::NotFound(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_blob_from_remaining_replica() {
+        let memory = memory_icda(2, 2, None).await;
+
+        let blob = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 15];
+        let blob_key = memory
+            .icda
+            .push_blob_to_canisters(blob.clone())
+            .await
+            .unwrap();
+
+        memory.storage_canisters[0].set_available(false);
+        let got = memory.icda.get_blob_from_canisters(blob_key).await.unwrap();
+        assert_eq!(blob, got);
+    }
+
+    #[tokio::test]
+    async fn test_get_blob_with_untrusted_key() {
+        let memory = memory_icda(1, 1, None).await;
+        let blob_key = memory
+            .icda
+            .push_blob_to_canisters(vec![1; 64])
+            .await
+            .unwrap();
+
+        let mut unknown = blob_key.clone();
+        unknown.routing_info.host_canisters = vec![Principal::anonymous()];
+        assert!(matches!(
+            memory.icda.get_blob_from_canisters(unknown).await,
+            Err(IcdaError::UnknownCanister(_))
+        ));
+
+        let mut expired = blob_key;
+        expired.expiry_timestamp = 0;
+        assert!(matches!(
+            memory.icda.get_blob_from_canisters(expired).await,
+            Err(IcdaError::Expired { .. })
+        ));
+    }
+
+    // the clock jumps over HEDGE_DELAY once every request is idle
+    #[tokio::test(start_paused = true)]
+    async fn test_hedged_read_skips_slow_replica() {
+        let slow = async {
+            futures::future::pending::<()>().await;
+            Ok(0)
+        };
+        let requests = vec![
+            slow.boxed(),
+            async { Err(IcdaError::NotFound("blob".to_string())) }.boxed(),
+            async { Ok(2) }.boxed(),
+        ];
+
+        let done = ICDA::hedged(requests, 1).await.unwrap();
+        assert_eq!(done, vec![(2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_push_returns_at_write_quorum() {
+        let memory = memory_icda(3, 2, None).await;
+        memory.storage_canisters[2].set_available(false);
+
+        let blob = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 15];
+        let blob_key = memory
+            .icda
+            .push_blob_to_canisters(blob.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(blob_key.routing_info.host_canisters.len(), 3);
+        assert!(memory.storage_canisters[0].contains(&blob_key.digest));
+        assert!(memory.storage_canisters[1].contains(&blob_key.digest));
+        assert!(!memory.storage_canisters[2].contains(&blob_key.digest));
+    }
+
+    #[tokio::test]
+    async fn test_get_erasure_coded_blob_with_missing_shard() {
+        let erasure = ErasureConfig {
+            data_shards: 2,
+            parity_shards: 1,
+        };
+        let memory = memory_icda(3, 3, Some(erasure)).await;
+
+        let blob = (0..1024 * 1024 + 3)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let blob_key = memory
+            .icda
+            .push_blob_to_canisters(blob.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            blob_key
+                .routing_info
+                .erasure
+                .as_ref()
+                .unwrap()
+                .shard_digests
+                .len(),
+            3
+        );
+
+        memory.storage_canisters[0].set_available(false);
+        let got = memory.icda.get_blob_from_canisters(blob_key).await.unwrap();
+        assert_eq!(blob, got);
+    }
+
+    #[tokio::test]
+    async fn test_erasure_coded_blob_confirmed_by_shards() {
+        let erasure = ErasureConfig {
+            data_shards: 2,
+            parity_shards: 1,
+        };
+        let memory = memory_icda(3, 3, Some(erasure)).await;
+
+        // 3 shards per blob, 4 blobs fill a batch
+        let mut blob_keys = vec![];
+        for i in 1..=(CONFIRMATION_BATCH_SIZE / 3) as u8 {
+            let blob = (0..64u8).map(|j| j.wrapping_mul(i)).collect();
+            let blob_key = memory.icda.push_blob_to_canisters(blob).await.unwrap();
+            if blob_keys.is_empty() {
+                assert!(matches!(
+                    memory.icda.get_blob_key_confirmation(&blob_key).await,
+                    Ok(BlobConfirmation::Pending)
+                ));
+            }
+            blob_keys.push(blob_key);
+        }
+
+        for blob_key in blob_keys.iter() {
+            // the blob digest itself is never reported
+            assert!(matches!(
+                memory.icda.get_blob_confirmation(blob_key.digest).await,
+                Ok(ConfirmationStatus::Invalid)
+            ));
+            match memory.icda.get_blob_key_confirmation(blob_key).await {
+                Ok(BlobConfirmation::Confirmed(confirmations)) => {
+                    assert_eq!(confirmations.len(), 3)
+                }
+                res => panic!("unexpected confirmation: {:?}", res),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_erasure_needs_canister_replica_quorum_1() {
+        let signature = Arc::new(MemorySignatureCanister::new(Principal::from_slice(&[0])));
+        let mut signature_config = signature.get_config().await.unwrap();
+        signature_config.replica_quorum = Some(2);
+        signature.update_config(&signature_config).await.unwrap();
+
+        let collection = (1..=3u8)
+            .map(|i| CanisterInfo {
+                canister_id: Principal::from_slice(&[i]),
+                subnet: None,
+            })
+            .collect::<Vec<_>>();
+        let mut builder = ICDA::builder()
+            .with_config(IcdaConfig {
+                collections: vec![collection.clone()],
+                replica_num: 3,
+                write_quorum: 3,
+                signature_canisters: vec![signature.canister_id()],
+                erasure: Some(ErasureConfig {
+                    data_shards: 2,
+                    parity_shards: 1,
+                }),
+                ..IcdaConfig::default()
+            })
+            .with_signature_client(signature.clone())
+            .start_background_tasks(false);
+        for info in collection {
+            builder = builder.with_storage_client(Arc::new(MemoryStorageCanister::new(
+                info.canister_id,
+                vec![signature.clone()],
+            )));
+        }
+
+        assert!(matches!(
+            builder.build().await,
+            Err(IcdaError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_confirmation_after_full_batch() {
+        let memory = memory_icda(1, 1, None).await;
+
+        let mut blob_keys = vec![];
+        for i in 0..CONFIRMATION_BATCH_SIZE as u8 {
+            let blob_key = memory
+                .icda
+                .push_blob_to_canisters(vec![i; 64])
+                .await
+                .unwrap();
+
+            // the batch is not sealed until it is full
+            if blob_keys.is_empty() {
+                assert!(matches!(
+                    memory.icda.get_blob_confirmation(blob_key.digest).await,
+                    Ok(ConfirmationStatus::Pending)
+                ));
+            }
+            blob_keys.push(blob_key);
+        }
+
+        for blob_key in blob_keys.iter() {
+            match memory.icda.get_blob_confirmation(blob_key.digest).await {
+                Ok(ConfirmationStatus::Confirmed(confirmation)) => {
+                    assert_eq!(confirmation.proof.leaf_digest, blob_key.digest);
+                    assert_eq!(confirmation.batch_index, 1);
+                }
+                res => panic!("unexpected confirmation: {:?}", res),
+            }
+        }
+
+        assert!(matches!(
+            memory.icda.get_blob_confirmation([0; 32]).await,
+            Ok(ConfirmationStatus::Invalid)
+        ));
+    }
+}
@@ -1,3 +1,9 @@
 mod backup;
 pub mod canister_interface;
+pub mod config;
+pub mod cycle_monitor;
+pub mod erasure;
+pub mod error;
 pub mod icda;
+pub mod placement;
+pub mod verify;
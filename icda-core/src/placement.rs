@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use candid::Principal;
+use futures::future::join_all;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::canister_interface::storage::{StorageClient, StorageStats};
+use crate::config::{CanisterInfo, IcdaConfig};
+use crate::error::{IcdaError, Result};
+
+// a canister that failed an upload is avoided for this long
+const FAILURE_BACKOFF: Duration = Duration::from_secs(300);
+// storage canister stats are queried at most this often
+const STATS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// points of every canister on the hash ring
+const VIRTUAL_NODES: u32 = 64;
+
+/// The [`PlacementPolicy`] built from an [`IcdaConfig`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    /// The collections in turn.
+    #[default]
+    RoundRobin,
+    /// The collection whose fullest canister holds the fewest blobs.
+    LeastLoaded,
+    /// Canisters of all collections, one per subnet.
+    SubnetDiverse,
+    /// Canisters following the digest on a hash ring.
+    ConsistentHash,
+}
+
+/// Chooses the storage canisters a blob is pushed to.
+#[async_trait]
+pub trait PlacementPolicy: Send + Sync {
+    /// `count` canisters for the blob, in shard order if it is erasure coded.
+    async fn select(&self, digest: &[u8; 32], count: usize) -> Result<Vec<Principal>>;
+
+    /// Called with the result of every upload to a canister.
+    fn observe(&self, _canister_id: Principal, _success: bool) {}
+}
+
+pub fn placement_policy(
+    config: &IcdaConfig,
+    storage_canisters: &HashMap<Principal, Arc<dyn StorageClient>>,
+) -> Arc<dyn PlacementPolicy> {
+    match config.placement {
+        Placement::RoundRobin => Arc::new(RoundRobin::new(config)),
+        Placement::LeastLoaded => Arc::new(LeastLoaded::new(config, storage_canisters.clone())),
+        Placement::SubnetDiverse => Arc::new(SubnetDiverse::new(config)),
+        Placement::ConsistentHash => Arc::new(ConsistentHash::new(config)),
+    }
+}
+
+/// Canisters whose last upload failed less than `FAILURE_BACKOFF` ago.
+#[derive(Default)]
+struct RecentFailures(Mutex<HashMap<Principal, Instant>>);
+
+impl RecentFailures {
+    fn observe(&self, canister_id: Principal, success: bool) {
+        let mut failures = self.0.lock().unwrap();
+        if success {
+            failures.remove(&canister_id);
+        } else {
+            failures.insert(canister_id, Instant::now());
+        }
+    }
+
+    fn contains(&self, canister_id: &Principal) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(canister_id)
+            .is_some_and(|failed_at| failed_at.elapsed() < FAILURE_BACKOFF)
+    }
+}
+
+fn collection_ids(config: &IcdaConfig) -> Vec<Vec<Principal>> {
+    config
+        .collections
+        .iter()
+        .map(|collection| collection.iter().map(|info| info.canister_id).collect())
+        .collect()
+}
+
+fn take_collection(collection: &[Principal], count: usize) -> Result<Vec<Principal>> {
+    if collection.len() < count {
+        return Err(IcdaError::InvalidConfig(format!(
+            "placement: collection has {} canisters, {} needed",
+            collection.len(),
+            count
+        )));
+    }
+    Ok(collection[..count].to_vec())
+}
+
+// first `count` canisters of `order` on distinct subnets, recently failed ones only if needed
+fn pick_on_distinct_subnets(
+    order: &[&CanisterInfo],
+    count: usize,
+    failures: &RecentFailures,
+) -> Result<Vec<Principal>> {
+    let mut picked: Vec<&CanisterInfo> = Vec::with_capacity(count);
+    for allow_failed in [false, true] {
+        for info in order.iter() {
+            if picked.len() == count {
+                break;
+            }
+            let taken = picked.iter().any(|p| {
+                p.canister_id == info.canister_id
+                    || (info.subnet.is_some() && p.subnet == info.subnet)
+            });
+            if taken || (!allow_failed && failures.contains(&info.canister_id)) {
+                continue;
+            }
+            picked.push(info);
+        }
+    }
+
+    if picked.len() < count {
+        return Err(IcdaError::InvalidConfig(format!(
+            "placement: {} canisters on distinct subnets, {} needed",
+            picked.len(),
+            count
+        )));
+    }
+    Ok(picked.into_iter().map(|info| info.canister_id).collect())
+}
+
+/// Every blob goes to the next collection.
+pub struct RoundRobin {
+    collections: Vec<Vec<Principal>>,
+    index: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new(config: &IcdaConfig) -> Self {
+        Self {
+            collections: collection_ids(config),
+            index: AtomicUsize::new(random::<usize>()),
+        }
+    }
+}
+
+#[async_trait]
+impl PlacementPolicy for RoundRobin {
+    async fn select(&self, _digest: &[u8; 32], count: usize) -> Result<Vec<Principal>> {
+        let index = self.index.fetch_add(1, Ordering::Relaxed) % self.collections.len();
+        take_collection(&self.collections[index], count)
+    }
+}
+
+/// Picks the collection whose fullest canister is the emptiest, relative to
+/// `canister_storage_threshold`. Collections with a recently failed canister
+/// are only picked if every collection has one.
+pub struct LeastLoaded {
+    collections: Vec<Vec<Principal>>,
+    storage_canisters: HashMap<Principal, Arc<dyn StorageClient>>,
+    // stats of the last refresh, blob_count is bumped for every selection since
+    stats: Mutex<(Option<Instant>, HashMap<Principal, StorageStats>)>,
+    failures: RecentFailures,
+}
+
+impl LeastLoaded {
+    pub fn new(
+        config: &IcdaConfig,
+        storage_canisters: HashMap<Principal, Arc<dyn StorageClient>>,
+    ) -> Self {
+        Self {
+            collections: collection_ids(config),
+            storage_canisters,
+            stats: Mutex::new((None, HashMap::new())),
+            failures: RecentFailures::default(),
+        }
+    }
+
+    async fn refresh_stats(&self) {
+        let refreshed_at = self.stats.lock().unwrap().0;
+        let stale = match refreshed_at {
+            Some(refreshed_at) => refreshed_at.elapsed() >= STATS_REFRESH_INTERVAL,
+            None => true,
+        };
+        if !stale {
+            return;
+        }
+
+        let tasks = self
+            .storage_canisters
+            .values()
+            .map(|sc| async move { (sc.canister_id(), sc.stats().await) });
+        let mut stats = HashMap::with_capacity(self.storage_canisters.len());
+        for (canister_id, res) in join_all(tasks).await {
+            match res {
+                Ok(s) => {
+                    stats.insert(canister_id, s);
+                }
+                Err(e) => {
+                    warn!(
+                        "ICDA LeastLoaded: storage canister: {}, failed to get stats: {:?}",
+                        canister_id, e
+                    );
+                    self.failures.observe(canister_id, false);
+                }
+            }
+        }
+
+        *self.stats.lock().unwrap() = (Some(Instant::now()), stats);
+    }
+
+    // permille of the threshold, unknown canisters count as full
+    fn load(stats: &HashMap<Principal, StorageStats>, canister_id: &Principal) -> u64 {
+        match stats.get(canister_id) {
+            Some(s) => s.blob_count.saturating_mul(1000) / (s.threshold.max(1) as u64),
+            None => u64::MAX,
+        }
+    }
+}
+
+#[async_trait]
+impl PlacementPolicy for LeastLoaded {
+    async fn select(&self, _digest: &[u8; 32], count: usize) -> Result<Vec<Principal>> {
+        self.refresh_stats().await;
+
+        let mut guard = self.stats.lock().unwrap();
+        let stats = &mut guard.1;
+        let collection = self
+            .collections
+            .iter()
+            .min_by_key(|collection| {
+                let failed = collection.iter().any(|cid| self.failures.contains(cid));
+                let load = collection
+                    .iter()
+                    .map(|cid| Self::load(stats, cid))
+                    .max()
+                    .unwrap_or(u64::MAX);
+                (failed, load)
+            })
+            .ok_or_else(|| {
+                IcdaError::InvalidConfig("placement: no storage canister collection".to_string())
+            })?;
+
+        let selected = take_collection(collection, count)?;
+        for cid in selected.iter() {
+            if let Some(s) = stats.get_mut(cid) {
+                s.blob_count += 1;
+            }
+        }
+        Ok(selected)
+    }
+
+    fn observe(&self, canister_id: Principal, success: bool) {
+        self.failures.observe(canister_id, success);
+    }
+}
+
+/// Ignores the collections and spreads the replicas of a blob over distinct
+/// subnets, starting one canister further for every blob.
+pub struct SubnetDiverse {
+    canisters: Vec<CanisterInfo>,
+    index: AtomicUsize,
+    failures: RecentFailures,
+}
+
+impl SubnetDiverse {
+    pub fn new(config: &IcdaConfig) -> Self {
+        Self {
+            canisters: config.collections.iter().flatten().cloned().collect(),
+            index: AtomicUsize::new(random::<usize>()),
+            failures: RecentFailures::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl PlacementPolicy for SubnetDiverse {
+    async fn select(&self, _digest: &[u8; 32], count: usize) -> Result<Vec<Principal>> {
+        let start = self.index.fetch_add(1, Ordering::Relaxed) % self.canisters.len();
+        let order = self.canisters[start..]
+            .iter()
+            .chain(self.canisters[..start].iter())
+            .collect::<Vec<_>>();
+        pick_on_distinct_subnets(&order, count, &self.failures)
+    }
+
+    fn observe(&self, canister_id: Principal, success: bool) {
+        self.failures.observe(canister_id, success);
+    }
+}
+
+/// Hash ring of all canisters: a blob goes to the canisters following its
+/// digest, so adding a canister only moves the blobs of its ring segments.
+pub struct ConsistentHash {
+    canisters: Vec<CanisterInfo>,
+    // (point, index into canisters), sorted by point
+    ring: Vec<(u64, usize)>,
+    failures: RecentFailures,
+}
+
+impl ConsistentHash {
+    pub fn new(config: &IcdaConfig) -> Self {
+        let canisters: Vec<CanisterInfo> = config.collections.iter().flatten().cloned().collect();
+
+        let mut ring = Vec::with_capacity(canisters.len() * VIRTUAL_NODES as usize);
+        for (index, info) in canisters.iter().enumerate() {
+            for node in 0..VIRTUAL_NODES {
+                let mut hasher = Sha256::new();
+                hasher.update(info.canister_id.as_slice());
+                hasher.update(node.to_be_bytes());
+                ring.push((Self::point(&hasher.finalize().into()), index));
+            }
+        }
+        ring.sort();
+
+        Self {
+            canisters,
+            ring,
+            failures: RecentFailures::default(),
+        }
+    }
+
+    fn point(hash: &[u8; 32]) -> u64 {
+        u64::from_be_bytes(hash[..8].try_into().unwrap())
+    }
+}
+
+#[async_trait]
+impl PlacementPolicy for ConsistentHash {
+    async fn select(&self, digest: &[u8; 32], count: usize) -> Result<Vec<Principal>> {
+        let point = Self::point(digest);
+        let start = self.ring.partition_point(|(p, _)| *p < point);
+        let order = self.ring[start..]
+            .iter()
+            .chain(self.ring[..start].iter())
+            .map(|(_, index)| &self.canisters[*index])
+            .collect::<Vec<_>>();
+        pick_on_distinct_subnets(&order, count, &self.failures)
+    }
+
+    fn observe(&self, canister_id: Principal, success: bool) {
+        self.failures.observe(canister_id, success);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 6 canisters on 3 subnets, in collections of 2
+    fn config() -> IcdaConfig {
+        let info = |i: u8| CanisterInfo {
+            canister_id: Principal::from_slice(&[i]),
+            subnet: Some(Principal::from_slice(&[100 + i % 3])),
+        };
+        IcdaConfig {
+            collections: vec![
+                vec![info(0), info(1)],
+                vec![info(2), info(3)],
+                vec![info(4), info(5)],
+            ],
+            replica_num: 2,
+            ..IcdaConfig::default()
+        }
+    }
+
+    fn subnet(config: &IcdaConfig, canister_id: &Principal) -> Option<Principal> {
+        config
+            .collections
+            .iter()
+            .flatten()
+            .find(|info| info.canister_id == *canister_id)
+            .and_then(|info| info.subnet)
+    }
+
+    #[tokio::test]
+    async fn test_subnet_diverse_avoids_failed_canisters() {
+        let config = config();
+        let policy = SubnetDiverse::new(&config);
+        policy.observe(Principal::from_slice(&[0]), false);
+
+        for _ in 0..6 {
+            let selected = policy.select(&[0; 32], 3).await.unwrap();
+            assert!(!selected.contains(&Principal::from_slice(&[0])));
+            let mut subnets = selected
+                .iter()
+                .map(|cid| subnet(&config, cid))
+                .collect::<Vec<_>>();
+            subnets.sort();
+            subnets.dedup();
+            assert_eq!(subnets.len(), 3);
+        }
+        assert!(policy.select(&[0; 32], 4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_consistent_hash_is_stable() {
+        let config = config();
+        let policy = ConsistentHash::new(&config);
+
+        let digest: [u8; 32] = Sha256::digest(b"blob").into();
+        let selected = policy.select(&digest, 2).await.unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_ne!(subnet(&config, &selected[0]), subnet(&config, &selected[1]));
+        assert_eq!(policy.select(&digest, 2).await.unwrap(), selected);
+    }
+}
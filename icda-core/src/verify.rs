@@ -0,0 +1,216 @@
+use rs_merkle::algorithms::Sha256;
+use rs_merkle::MerkleProof;
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey, Secp256k1};
+use thiserror::Error;
+
+use crate::canister_interface::signature::Confirmation;
+
+/// Why a confirmation did not verify.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error("malformed public key: {0}")]
+    MalformedPublicKey(String),
+
+    #[error("malformed signature: {0}")]
+    MalformedSignature(String),
+
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+
+    #[error("confirmation is for digest {actual}, expected {expected}")]
+    DigestMismatch { expected: String, actual: String },
+
+    #[error("leaf index {leaf_index} is out of {leaf_count} leaves")]
+    InvalidLeafIndex {
+        leaf_index: usize,
+        leaf_count: usize,
+    },
+
+    #[error("malformed merkle proof: {0}")]
+    MalformedProof(String),
+
+    #[error("merkle proof does not match the batch root")]
+    InvalidProof,
+}
+
+/// Verifies a confirmation offline: it must be for `expected_digest`, the batch header
+/// must be signed by `public_key` (SEC1 encoded secp256k1) and the merkle proof must
+/// lead from the blob's leaf to the signed root.
+///
+/// Legacy confirmations are checked in the old format: the root is signed and the leaf
+/// is the bare digest.
+pub fn verify(
+    confirmation: &Confirmation,
+    public_key: &[u8],
+    expected_digest: &[u8; 32],
+) -> Result<(), VerifyError> {
+    let proof = &confirmation.proof;
+    if proof.leaf_digest != *expected_digest {
+        return Err(VerifyError::DigestMismatch {
+            expected: hex::encode(expected_digest),
+            actual: hex::encode(proof.leaf_digest),
+        });
+    }
+
+    // verify signature
+    let pubkey = PublicKey::from_slice(public_key)
+        .map_err(|e| VerifyError::MalformedPublicKey(e.to_string()))?;
+    let compact_sig = hex::decode(&confirmation.signature)
+        .map_err(|e| VerifyError::MalformedSignature(e.to_string()))?;
+    let sig = Signature::from_compact(&compact_sig)
+        .map_err(|e| VerifyError::MalformedSignature(e.to_string()))?;
+    let msg = Message::from_digest(confirmation.signed_hash());
+    Secp256k1::verification_only()
+        .verify_ecdsa(&msg, &sig, &pubkey)
+        .map_err(|e| VerifyError::InvalidSignature(e.to_string()))?;
+
+    // verify merkle proof
+    if proof.leaf_index >= proof.leaf_count {
+        return Err(VerifyError::InvalidLeafIndex {
+            leaf_index: proof.leaf_index,
+            leaf_count: proof.leaf_count,
+        });
+    }
+    let merkle_proof = MerkleProof::<Sha256>::try_from(proof.proof_bytes.as_slice())
+        .map_err(|e| VerifyError::MalformedProof(e.to_string()))?;
+    let leaf = if confirmation.legacy {
+        proof.leaf_digest
+    } else {
+        proof.leaf_hash()
+    };
+    if !merkle_proof.verify(
+        confirmation.root,
+        &[proof.leaf_index],
+        &[leaf],
+        proof.leaf_count,
+    ) {
+        return Err(VerifyError::InvalidProof);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::canister_interface::signature::{header_hash, Proof};
+    use candid::Principal;
+    use rs_merkle::MerkleTree;
+    use secp256k1::SecretKey;
+
+    // a signed batch of `leaf_count` blobs and the confirmation of the blob at `leaf_index`
+    fn confirmation(leaf_count: usize, leaf_index: usize) -> (Confirmation, Vec<u8>) {
+        let proofs = (0..leaf_count as u8)
+            .map(|i| Proof {
+                proof_bytes: vec![],
+                leaf_index: i as usize,
+                leaf_count,
+                leaf_digest: [i; 32],
+                blob_size: 64,
+                timestamp: i as u128,
+                storage_canisters: vec![Principal::anonymous()],
+            })
+            .collect::<Vec<_>>();
+        let tree = MerkleTree::<Sha256>::from_leaves(
+            &proofs.iter().map(Proof::leaf_hash).collect::<Vec<_>>(),
+        );
+        let root = tree.root().unwrap();
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let msg = Message::from_digest(header_hash(1, &root, &[0; 32]));
+        let signature = secp.sign_ecdsa(&msg, &secret_key).serialize_compact();
+
+        let mut proof = proofs[leaf_index].clone();
+        proof.proof_bytes = tree.proof(&[leaf_index]).to_bytes();
+        let confirmation = Confirmation {
+            root,
+            proof,
+            signature: hex::encode(signature),
+            batch_index: 1,
+            prev_hash: [0; 32],
+            legacy: false,
+        };
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        (confirmation, public_key.serialize().to_vec())
+    }
+
+    #[test]
+    fn test_verify() {
+        // a batch that was sealed before it was full
+        let (confirmation, public_key) = confirmation(5, 3);
+        assert_eq!(verify(&confirmation, &public_key, &[3; 32]), Ok(()));
+
+        assert!(matches!(
+            verify(&confirmation, &public_key, &[4; 32]),
+            Err(VerifyError::DigestMismatch { .. })
+        ));
+        assert!(matches!(
+            verify(&confirmation, &public_key[1..], &[3; 32]),
+            Err(VerifyError::MalformedPublicKey(_))
+        ));
+
+        let mut malformed = confirmation.clone();
+        malformed.signature = "not hex".to_string();
+        assert!(matches!(
+            verify(&malformed, &public_key, &[3; 32]),
+            Err(VerifyError::MalformedSignature(_))
+        ));
+
+        let mut tampered = confirmation.clone();
+        tampered.proof.blob_size += 1;
+        assert_eq!(
+            verify(&tampered, &public_key, &[3; 32]),
+            Err(VerifyError::InvalidProof)
+        );
+
+        let mut out_of_range = confirmation;
+        out_of_range.proof.leaf_index = 5;
+        assert!(matches!(
+            verify(&out_of_range, &public_key, &[3; 32]),
+            Err(VerifyError::InvalidLeafIndex { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_legacy() {
+        // signed before the upgrade: the leaves are the digests and the root is signed
+        let digests = (0..5u8).map(|i| [i; 32]).collect::<Vec<_>>();
+        let tree = MerkleTree::<Sha256>::from_leaves(&digests);
+        let root = tree.root().unwrap();
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let signature = secp
+            .sign_ecdsa(&Message::from_digest(root), &secret_key)
+            .serialize_compact();
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key)
+            .serialize()
+            .to_vec();
+
+        let confirmation = Confirmation {
+            root,
+            proof: Proof {
+                proof_bytes: tree.proof(&[3]).to_bytes(),
+                leaf_index: 3,
+                leaf_count: 5,
+                leaf_digest: [3; 32],
+                blob_size: 0,
+                timestamp: 0,
+                storage_canisters: vec![],
+            },
+            signature: hex::encode(signature),
+            batch_index: 2,
+            prev_hash: [0; 32],
+            legacy: true,
+        };
+        assert_eq!(verify(&confirmation, &public_key, &[3; 32]), Ok(()));
+
+        let mut current = confirmation;
+        current.legacy = false;
+        assert!(matches!(
+            verify(&current, &public_key, &[3; 32]),
+            Err(VerifyError::InvalidSignature(_))
+        ));
+    }
+}
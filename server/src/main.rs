@@ -20,6 +20,8 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
+use icda_core::config::IcdaConfig;
+use icda_core::cycle_monitor::CycleMonitor;
 use icda_core::icda::ICDA;
 use server::storage::{LocalStorage, S3Storage, Storage};
 use tonic::transport::Server;
@@ -85,6 +87,10 @@ pub struct S3 {
 pub struct IC {
     #[arg(long)]
     pem_path: String,
+
+    /// Canister topology (TOML or JSON), defaults to the compiled-in canisters.
+    #[arg(long)]
+    icda_config: Option<PathBuf>,
 }
 
 /// Params for using local database for persistence.
@@ -211,9 +217,18 @@ async fn main() -> Result<()> {
     let storage: Box<dyn Storage> = match cmd {
         Cmd::S3(S3 { profile, bucket }) => Box::new(S3Storage::new(profile, bucket).await),
         Cmd::Local(Local { db_path }) => Box::new(LocalStorage::new(db_path)?),
-        Cmd::IC(IC { pem_path }) => {
+        Cmd::IC(IC {
+            pem_path,
+            icda_config,
+        }) => {
             is_icda = true;
-            Box::new(ICDA::new(pem_path).await?)
+            let config = match icda_config {
+                Some(path) => IcdaConfig::from_file(path)?,
+                None => IcdaConfig::default(),
+            };
+            let icda = ICDA::new(pem_path, config).await?;
+            tokio::spawn(CycleMonitor::new(icda.clone()).start_monitor());
+            Box::new(icda)
         }
     };
 
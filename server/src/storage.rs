@@ -191,7 +191,7 @@ impl Storage for ICDA {
     // ATTENTION: the blob id type is BlobKey
     async fn get_blob(&self, blob_id: Vec<u8>) -> Result<Vec<u8>> {
         let key = serde_json::from_slice::<BlobKey>(&blob_id)?;
-        self.get_blob_from_canisters(key).await
+        Ok(self.get_blob_from_canisters(key).await?)
     }
 }
 